@@ -0,0 +1,19 @@
+CREATE TABLE "transactions" (
+                                id VARCHAR NOT NULL PRIMARY KEY,
+                                kind VARCHAR NOT NULL,
+                                sender VARCHAR,
+                                recipient VARCHAR,
+                                amount BIGINT NOT NULL,
+                                memo TEXT,
+                                payment_hash VARCHAR,
+                                bolt11 TEXT,
+                                room_id VARCHAR,
+                                event_id VARCHAR,
+                                status VARCHAR NOT NULL,
+                                date_created TEXT NOT NULL,
+                                date_updated TEXT NOT NULL
+);
+
+CREATE INDEX transactions_sender_idx ON "transactions" (sender);
+CREATE INDEX transactions_recipient_idx ON "transactions" (recipient);
+CREATE INDEX transactions_payment_hash_idx ON "transactions" (payment_hash);
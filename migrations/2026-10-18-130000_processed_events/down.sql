@@ -0,0 +1 @@
+DROP table "processed_events"
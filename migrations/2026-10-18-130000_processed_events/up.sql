@@ -0,0 +1,5 @@
+CREATE TABLE "processed_events" (
+                                             event_id VARCHAR NOT NULL PRIMARY KEY,
+                                             room_id VARCHAR,
+                                             date_created TEXT NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE "vouchers" ADD COLUMN transaction_id VARCHAR;
@@ -0,0 +1,2 @@
+DROP INDEX transactions_sender_created_at_idx;
+ALTER TABLE "transactions" DROP COLUMN created_at;
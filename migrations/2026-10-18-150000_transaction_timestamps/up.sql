@@ -0,0 +1,6 @@
+ALTER TABLE "transactions" ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+
+-- date_created holds the chrono display format, its first 19 characters are a plain UTC timestamp
+UPDATE "transactions" SET created_at = COALESCE(CAST(strftime('%s', substr(date_created, 1, 19)) AS BIGINT), 0);
+
+CREATE INDEX transactions_sender_created_at_idx ON "transactions" (sender, created_at);
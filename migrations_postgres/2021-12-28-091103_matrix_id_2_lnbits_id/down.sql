@@ -0,0 +1 @@
+DROP table "matrix_id_2_lnbits_id"
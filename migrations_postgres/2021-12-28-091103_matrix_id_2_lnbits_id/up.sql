@@ -0,0 +1,6 @@
+CREATE TABLE "matrix_id_2_lnbits_id" (
+                                             matrix_id VARCHAR NOT NULL PRIMARY KEY,
+                                             lnbits_id VARCHAR NOT NULL,
+                                             lnbits_admin VARCHAR NOT NULL,
+                                             date_created TEXT NOT NULL
+);
@@ -0,0 +1 @@
+DROP table "notification_settings"
@@ -0,0 +1,7 @@
+CREATE TABLE "notification_settings" (
+                                             matrix_id VARCHAR NOT NULL PRIMARY KEY,
+                                             dm_notifications BOOLEAN NOT NULL DEFAULT FALSE,
+                                             dm_room_id VARCHAR,
+                                             date_created TEXT NOT NULL,
+                                             date_updated TEXT NOT NULL
+);
@@ -0,0 +1 @@
+DROP table "spending_limits"
@@ -0,0 +1,7 @@
+CREATE TABLE "spending_limits" (
+                                             matrix_id VARCHAR NOT NULL PRIMARY KEY,
+                                             max_payment_amount BIGINT,
+                                             max_daily_amount BIGINT,
+                                             date_created TEXT NOT NULL,
+                                             date_updated TEXT NOT NULL
+);
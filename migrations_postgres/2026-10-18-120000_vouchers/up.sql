@@ -0,0 +1,12 @@
+CREATE TABLE "vouchers" (
+                                             id VARCHAR NOT NULL PRIMARY KEY,
+                                             matrix_id VARCHAR NOT NULL,
+                                             amount BIGINT NOT NULL,
+                                             lnurl TEXT NOT NULL,
+                                             status VARCHAR NOT NULL,
+                                             expires_at TEXT NOT NULL,
+                                             date_created TEXT NOT NULL,
+                                             date_updated TEXT NOT NULL
+);
+
+CREATE INDEX vouchers_status_idx ON "vouchers" (status);
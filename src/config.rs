@@ -1,40 +1,109 @@
 pub mod config {
 
-    use clap::{Arg, Command};
+    use clap::{Arg, ArgAction, Command};
 
     #[derive(Clone, Debug)]
     pub struct Config {
         pub matrix_server: String,
         pub matrix_username: String,
-        pub matrix_password: String,
+        pub matrix_auth_mode: String,
+        pub matrix_password: Option<String>,
+        pub matrix_access_token: Option<String>,
+        pub matrix_device_id: Option<String>,
+        pub appservice_registration: Option<String>,
+        pub appservice_listen_address: Option<String>,
+        pub appservice_url: Option<String>,
+        pub generate_appservice_registration: bool,
+        pub matrix_store_path: String,
+        pub matrix_store_passphrase: Option<String>,
+        pub matrix_admin_users: Vec<String>,
+        pub matrix_recovery_key: Option<String>,
+        pub store_recovery_key: bool,
         pub lnbits_url: String,
         pub lnbits_x_api_key: String,
         pub database_url: String,
+        pub database_encryption_key: String,
+        pub rotate_encryption_key: Option<String>,
         pub debug_level: String,
         pub donate_user: Option<String>,
-        pub btc_donation_address: String
+        pub btc_donation_address: String,
+        pub max_payment_amount: Option<u64>,
+        pub max_daily_amount: Option<u64>,
+        pub confirmation_threshold: Option<u64>,
+        pub network: String,
+        pub lnurl_listen_address: Option<String>,
+        pub lnurl_public_url: Option<String>,
+        pub lnurl_matrix_server_name: Option<String>,
+        pub require_verified_devices: bool,
+        pub migrate_only: bool
     }
 
     impl Config {
         pub fn new(matrix_server: &str,
                    matrix_username: &str,
-               matrix_password: &str,
+               matrix_auth_mode: &str,
+               matrix_password: Option<&String>,
+               matrix_access_token: Option<&String>,
+               matrix_device_id: Option<&String>,
+               appservice_registration: Option<&String>,
+               appservice_listen_address: Option<&String>,
+               appservice_url: Option<&String>,
+               generate_appservice_registration: bool,
+               matrix_store_path: &str,
+               matrix_store_passphrase: Option<&String>,
+               matrix_admin_users: &[String],
+               matrix_recovery_key: Option<&String>,
+               store_recovery_key: bool,
                lnbits_url: &str,
                lnbits_x_api_key: &str,
                database_url: &str,
+               database_encryption_key: &str,
+               rotate_encryption_key: Option<&String>,
                debug_level: &str,
                donate_user: Option<&String>,
-               btc_donation_address: &str) -> Config {
+               btc_donation_address: &str,
+               max_payment_amount: Option<&u64>,
+               max_daily_amount: Option<&u64>,
+               confirmation_threshold: Option<&u64>,
+               network: &str,
+               lnurl_listen_address: Option<&String>,
+               lnurl_public_url: Option<&String>,
+               lnurl_matrix_server_name: Option<&String>,
+               require_verified_devices: bool,
+               migrate_only: bool) -> Config {
             Config {
                 matrix_server: matrix_server.to_string(),
                 matrix_username: matrix_username.to_string(),
-                matrix_password: matrix_password.to_string(),
+                matrix_auth_mode: matrix_auth_mode.to_string(),
+                matrix_password: matrix_password.map(|s| s.to_string()),
+                matrix_access_token: matrix_access_token.map(|s| s.to_string()),
+                matrix_device_id: matrix_device_id.map(|s| s.to_string()),
+                appservice_registration: appservice_registration.map(|s| s.to_string()),
+                appservice_listen_address: appservice_listen_address.map(|s| s.to_string()),
+                appservice_url: appservice_url.map(|s| s.to_string()),
+                generate_appservice_registration,
+                matrix_store_path: matrix_store_path.to_string(),
+                matrix_store_passphrase: matrix_store_passphrase.map(|s| s.to_string()),
+                matrix_admin_users: matrix_admin_users.to_vec(),
+                matrix_recovery_key: matrix_recovery_key.map(|s| s.to_string()),
+                store_recovery_key,
                 lnbits_url: lnbits_url.to_string(),
                 lnbits_x_api_key: lnbits_x_api_key.to_string(),
                 database_url: database_url.to_string(),
+                database_encryption_key: database_encryption_key.to_string(),
+                rotate_encryption_key: rotate_encryption_key.map(|s| s.to_string()),
                 debug_level: debug_level.to_string(),
                 donate_user: donate_user.map(|s| s.to_string()),
-                btc_donation_address: btc_donation_address.to_string()
+                btc_donation_address: btc_donation_address.to_string(),
+                max_payment_amount: max_payment_amount.copied(),
+                max_daily_amount: max_daily_amount.copied(),
+                confirmation_threshold: confirmation_threshold.copied(),
+                network: network.to_string(),
+                lnurl_listen_address: lnurl_listen_address.map(|s| s.to_string()),
+                lnurl_public_url: lnurl_public_url.map(|s| s.to_string()),
+                lnurl_matrix_server_name: lnurl_matrix_server_name.map(|s| s.to_string()),
+                require_verified_devices,
+                migrate_only
             }
         }
     }
@@ -58,11 +127,70 @@ pub mod config {
             .arg(Arg::new("matrix-username")
                 .long("matrix-username")
                 .required(true)
-                .help("Bot username"))
+                .help("Bot username, has to be the full user id i.e. @bot:example.org for the access-token and appservice modes"))
+            .arg(Arg::new("matrix-auth-mode")
+                .long("matrix-auth-mode")
+                .value_parser(["password", "access-token", "appservice"])
+                .default_value("password")
+                .required(false)
+                .help("How the bot authenticates against the homeserver"))
             .arg(Arg::new("matrix-password")
                 .long("matrix-password")
-                .required(true)
+                .required_if_eq("matrix-auth-mode", "password")
                 .help("Bot password"))
+            .arg(Arg::new("matrix-access-token")
+                .long("matrix-access-token")
+                .env("MLTB_MATRIX_ACCESS_TOKEN")
+                .required_if_eq("matrix-auth-mode", "access-token")
+                .help("Pre-issued access token of the bot, i.e. obtained through SSO"))
+            .arg(Arg::new("matrix-device-id")
+                .long("matrix-device-id")
+                .required_if_eq("matrix-auth-mode", "access-token")
+                .help("Device id the access token belongs to"))
+            .arg(Arg::new("appservice-registration")
+                .long("appservice-registration")
+                .required_if_eq("matrix-auth-mode", "appservice")
+                .help("Path of the appservice registration file"))
+            .arg(Arg::new("appservice-listen-address")
+                .long("appservice-listen-address")
+                .required_if_eq("matrix-auth-mode", "appservice")
+                .help("Address to receive appservice transactions on, i.e. 0.0.0.0:8081"))
+            .arg(Arg::new("appservice-url")
+                .long("appservice-url")
+                .required(false)
+                .help("The url the homeserver reaches the appservice at, written into a generated registration"))
+            .arg(Arg::new("generate-appservice-registration")
+                .long("generate-appservice-registration")
+                .action(ArgAction::SetTrue)
+                .requires_all(["appservice-registration", "appservice-url"])
+                .required(false)
+                .help("Write a new appservice registration file and exit"))
+            .arg(Arg::new("matrix-store-path")
+                .long("matrix-store-path")
+                .default_value("matrix-store")
+                .required(false)
+                .help("Directory to keep the matrix session, state and encryption keys in"))
+            .arg(Arg::new("matrix-store-passphrase")
+                .long("matrix-store-passphrase")
+                .env("MLTB_MATRIX_STORE_PASSPHRASE")
+                .required(false)
+                .help("Passphrase to encrypt the matrix store with"))
+            .arg(Arg::new("matrix-admin-users")
+                .long("matrix-admin-users")
+                .value_delimiter(',')
+                .action(ArgAction::Append)
+                .required(false)
+                .help("Comma separated full user ids that may verify the bot's device"))
+            .arg(Arg::new("matrix-recovery-key")
+                .long("matrix-recovery-key")
+                .env("MLTB_MATRIX_RECOVERY_KEY")
+                .required(false)
+                .help("Recovery key of the server side key backup"))
+            .arg(Arg::new("store-recovery-key")
+                .long("store-recovery-key")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .help("Create a server side key backup and write its recovery key into the matrix store"))
             .arg(Arg::new("lnbits-url")
                 .long("lnbits-url")
                 .required(true)
@@ -75,6 +203,15 @@ pub mod config {
                 .long("database-url")
                 .required(true)
                 .help("database url"))
+            .arg(Arg::new("database-encryption-key")
+                .long("database-encryption-key")
+                .env("MLTB_DATABASE_ENCRYPTION_KEY")
+                .required(true)
+                .help("Base64 encoded 32 byte key used to encrypt secrets stored in the database"))
+            .arg(Arg::new("rotate-encryption-key")
+                .long("rotate-encryption-key")
+                .required(false)
+                .help("Re-encrypt all stored secrets with this new base64 encoded key and exit"))
             .arg(Arg::new("debug-level")
                 .long("debug-level")
                 .default_value("Info")
@@ -89,6 +226,50 @@ pub mod config {
                 .default_value("bc1q72dzh04fwxx780w05twtmn5fxzegpawdn5zg3g")
                 .required(false)
                 .help("The BTC address to display for donations"))
+            .arg(Arg::new("max-payment-amount")
+                .long("max-payment-amount")
+                .value_parser(clap::value_parser!(u64))
+                .required(false)
+                .help("The default maximum amount of Sats a user can send in a single payment"))
+            .arg(Arg::new("max-daily-amount")
+                .long("max-daily-amount")
+                .value_parser(clap::value_parser!(u64))
+                .required(false)
+                .help("The default maximum amount of Sats a user can send within 24 hours"))
+            .arg(Arg::new("confirmation-threshold")
+                .long("confirmation-threshold")
+                .value_parser(clap::value_parser!(u64))
+                .required(false)
+                .help("Payments above this amount of Sats have to be confirmed by the sender, unconfirmed payments are kept in memory only"))
+            .arg(Arg::new("network")
+                .long("network")
+                .value_parser(["bitcoin", "testnet", "signet", "regtest"])
+                .default_value("bitcoin")
+                .required(false)
+                .help("The network the LNbits instance runs on, invoices for other networks are refused"))
+            .arg(Arg::new("lnurl-listen-address")
+                .long("lnurl-listen-address")
+                .required(false)
+                .requires_all(["lnurl-public-url", "lnurl-matrix-server-name"])
+                .help("Address to serve lightning addresses on, i.e. 0.0.0.0:8080"))
+            .arg(Arg::new("lnurl-public-url")
+                .long("lnurl-public-url")
+                .required(false)
+                .help("The url the lightning address server is reachable at, its domain is used for the addresses"))
+            .arg(Arg::new("lnurl-matrix-server-name")
+                .long("lnurl-matrix-server-name")
+                .required(false)
+                .help("The matrix server name of the users that get a lightning address"))
+            .arg(Arg::new("require-verified-devices")
+                .long("require-verified-devices")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .help("Refuse commands that move Sats when they come from unverified devices in encrypted rooms"))
+            .arg(Arg::new("migrate-only")
+                .long("migrate-only")
+                .action(ArgAction::SetTrue)
+                .required(false)
+                .help("Apply pending database migrations and exit"))
 
             .get_matches_from(args);
 
@@ -96,7 +277,33 @@ pub mod config {
 
         let matrix_username = matches.get_one::<String>("matrix-username").unwrap();
 
-        let matrix_password = matches.get_one::<String>("matrix-password").unwrap();
+        let matrix_auth_mode = matches.get_one::<String>("matrix-auth-mode").unwrap();
+
+        let matrix_password = matches.get_one::<String>("matrix-password");
+
+        let matrix_access_token = matches.get_one::<String>("matrix-access-token");
+
+        let matrix_device_id = matches.get_one::<String>("matrix-device-id");
+
+        let appservice_registration = matches.get_one::<String>("appservice-registration");
+
+        let appservice_listen_address = matches.get_one::<String>("appservice-listen-address");
+
+        let appservice_url = matches.get_one::<String>("appservice-url");
+
+        let generate_appservice_registration = matches.get_flag("generate-appservice-registration");
+
+        let matrix_store_path = matches.get_one::<String>("matrix-store-path").unwrap();
+
+        let matrix_store_passphrase = matches.get_one::<String>("matrix-store-passphrase");
+
+        let matrix_admin_users: Vec<String> = matches.get_many::<String>("matrix-admin-users")
+                                                     .map(|admin_users| admin_users.cloned().collect())
+                                                     .unwrap_or_default();
+
+        let matrix_recovery_key = matches.get_one::<String>("matrix-recovery-key");
+
+        let store_recovery_key = matches.get_flag("store-recovery-key");
 
         let lnbits_url = matches.get_one::<String>("lnbits-url").unwrap();
 
@@ -104,20 +311,65 @@ pub mod config {
 
         let database_url = matches.get_one::<String>("database-url").unwrap();
 
+        let database_encryption_key = matches.get_one::<String>("database-encryption-key").unwrap();
+
+        let rotate_encryption_key = matches.get_one::<String>("rotate-encryption-key");
+
         let debug_level = matches.get_one::<String>("debug-level").unwrap();
 
         let donate_user = matches.get_one::<String>("donate-user");
 
         let btc_donation_address = matches.get_one::<String>("btc-donation-address").unwrap();
 
+        let max_payment_amount = matches.get_one::<u64>("max-payment-amount");
+
+        let max_daily_amount = matches.get_one::<u64>("max-daily-amount");
+
+        let confirmation_threshold = matches.get_one::<u64>("confirmation-threshold");
+
+        let network = matches.get_one::<String>("network").unwrap();
+
+        let lnurl_listen_address = matches.get_one::<String>("lnurl-listen-address");
+
+        let lnurl_public_url = matches.get_one::<String>("lnurl-public-url");
+
+        let lnurl_matrix_server_name = matches.get_one::<String>("lnurl-matrix-server-name");
+
+        let require_verified_devices = matches.get_flag("require-verified-devices");
+
+        let migrate_only = matches.get_flag("migrate-only");
+
         Config::new(matrix_server,
                     matrix_username,
+                    matrix_auth_mode,
                     matrix_password,
+                    matrix_access_token,
+                    matrix_device_id,
+                    appservice_registration,
+                    appservice_listen_address,
+                    appservice_url,
+                    generate_appservice_registration,
+                    matrix_store_path,
+                    matrix_store_passphrase,
+                    &matrix_admin_users,
+                    matrix_recovery_key,
+                    store_recovery_key,
                     lnbits_url,
                     lnbits_x_api_key,
                     database_url,
+                    database_encryption_key,
+                    rotate_encryption_key,
                     debug_level,
                     donate_user,
-                    btc_donation_address)
+                    btc_donation_address,
+                    max_payment_amount,
+                    max_daily_amount,
+                    confirmation_threshold,
+                    network,
+                    lnurl_listen_address,
+                    lnurl_public_url,
+                    lnurl_matrix_server_name,
+                    require_verified_devices,
+                    migrate_only)
     }
 }
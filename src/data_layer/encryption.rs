@@ -0,0 +1,111 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::error::error::BotError;
+
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 24;
+
+// Encrypts single column values with XChaCha20-Poly1305. Stored values look like
+// 'enc:v1:<base64(nonce || ciphertext)>' so plaintext rows from older versions can be told apart.
+#[derive(Clone)]
+pub struct ColumnCipher {
+    cipher: XChaCha20Poly1305
+}
+
+impl ColumnCipher {
+    pub fn from_base64(key: &str) -> Result<ColumnCipher, BotError> {
+        let key = STANDARD.decode(key.trim())
+                          .map_err(|_| BotError::Internal("Encryption key is not valid base64".to_string()))?;
+        if key.len() != KEY_LENGTH {
+            return Err(BotError::Internal(format!("Encryption key must be {} bytes, got {}", KEY_LENGTH, key.len())));
+        }
+        let cipher = XChaCha20Poly1305::new_from_slice(key.as_slice())
+                                       .map_err(|_| BotError::Internal("Encryption key could not be used".to_string()))?;
+        Ok(ColumnCipher {
+            cipher
+        })
+    }
+
+    pub fn is_encrypted(value: &str) -> bool {
+        value.starts_with(ENCRYPTED_PREFIX)
+    }
+
+    // The associated data is the primary key of the row, so a ciphertext can not be copied over to another row.
+    pub fn encrypt(&self, plaintext: &str, associated_data: &str) -> Result<String, BotError> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, Payload { msg: plaintext.as_bytes(),
+                                                               aad: associated_data.as_bytes() })
+                                    .map_err(|_| BotError::Internal("Value could not be encrypted".to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(sealed)))
+    }
+
+    pub fn decrypt(&self, value: &str, associated_data: &str) -> Result<String, BotError> {
+        let encoded = value.strip_prefix(ENCRYPTED_PREFIX)
+                           .ok_or_else(|| BotError::Database("Value is not encrypted".to_string()))?;
+        let sealed = STANDARD.decode(encoded)
+                             .map_err(|_| BotError::Database("Encrypted value is not valid base64".to_string()))?;
+        if sealed.len() < NONCE_LENGTH {
+            return Err(BotError::Database("Encrypted value is truncated".to_string()));
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+        let plaintext = self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext,
+                                                                                 aad: associated_data.as_bytes() })
+                                   .map_err(|_| BotError::Database("Value could not be decrypted, wrong encryption key?".to_string()))?;
+        String::from_utf8(plaintext).map_err(|_| BotError::Database("Decrypted value is not valid UTF-8".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+    const OTHER_KEY: &str = "HxoZGBcWFRQTEhEQDw4NDAsKCQgHBgUEAwIBAB8eHRw=";
+
+    #[test]
+    fn encrypted_values_round_trip() {
+        let cipher = ColumnCipher::from_base64(KEY).unwrap();
+
+        let sealed = cipher.encrypt("admin-key", "@alice:example.com").unwrap();
+        assert!(sealed.starts_with(ENCRYPTED_PREFIX));
+        assert!(ColumnCipher::is_encrypted(sealed.as_str()));
+        assert_eq!(cipher.decrypt(sealed.as_str(), "@alice:example.com").unwrap(), "admin-key");
+
+        // A fresh nonce every time
+        assert_ne!(sealed, cipher.encrypt("admin-key", "@alice:example.com").unwrap());
+    }
+
+    #[test]
+    fn decryption_needs_the_same_key_and_row() {
+        let cipher = ColumnCipher::from_base64(KEY).unwrap();
+        let sealed = cipher.encrypt("admin-key", "@alice:example.com").unwrap();
+
+        assert!(cipher.decrypt(sealed.as_str(), "@bob:example.com").is_err());
+        assert!(ColumnCipher::from_base64(OTHER_KEY).unwrap().decrypt(sealed.as_str(), "@alice:example.com").is_err());
+    }
+
+    #[test]
+    fn plaintext_and_malformed_values_are_rejected() {
+        let cipher = ColumnCipher::from_base64(KEY).unwrap();
+
+        assert!(!ColumnCipher::is_encrypted("admin-key"));
+        assert!(cipher.decrypt("admin-key", "@alice:example.com").is_err());
+        assert!(cipher.decrypt("enc:v1:not base64", "@alice:example.com").is_err());
+        assert!(cipher.decrypt("enc:v1:AAAA", "@alice:example.com").is_err());
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes_of_base64() {
+        assert!(ColumnCipher::from_base64("not base64!").is_err());
+        assert!(ColumnCipher::from_base64("AAAA").is_err());
+        assert!(ColumnCipher::from_base64(format!(" {}\n", KEY).as_str()).is_ok());
+    }
+}
@@ -49,3 +49,120 @@ impl LNBitsId {
         }
     }
 }
+
+pub const TRANSACTION_KIND_SEND: &str = "send";
+pub const TRANSACTION_KIND_PAY: &str = "pay";
+pub const TRANSACTION_KIND_INVOICE: &str = "invoice";
+pub const TRANSACTION_KIND_VOUCHER: &str = "voucher";
+
+pub const TRANSACTION_STATUS_PENDING: &str = "pending";
+pub const TRANSACTION_STATUS_SETTLED: &str = "settled";
+pub const TRANSACTION_STATUS_FAILED: &str = "failed";
+pub const TRANSACTION_STATUS_EXPIRED: &str = "expired";
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct Transaction {
+    pub id: String,
+    pub kind: String,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    pub amount: i64,
+    pub memo: Option<String>,
+    pub payment_hash: Option<String>,
+    pub bolt11: Option<String>,
+    pub room_id: Option<String>,
+    pub event_id: Option<String>,
+    pub status: String,
+    pub date_created: String,
+    pub date_updated: String,
+    pub created_at: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = transactions)]
+pub struct NewTransaction {
+    pub id: String,
+    pub kind: String,
+    pub sender: Option<String>,
+    pub recipient: Option<String>,
+    pub amount: i64,
+    pub memo: Option<String>,
+    pub payment_hash: Option<String>,
+    pub bolt11: Option<String>,
+    pub room_id: Option<String>,
+    pub event_id: Option<String>,
+    pub status: String,
+    pub date_created: String,
+    pub date_updated: String,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct NotificationSettings {
+    pub matrix_id: String,
+    pub dm_notifications: bool,
+    pub dm_room_id: Option<String>,
+    pub date_created: String,
+    pub date_updated: String,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = notification_settings)]
+pub struct NewNotificationSettings<'a> {
+    pub matrix_id: &'a str,
+    pub dm_notifications: bool,
+    pub dm_room_id: Option<&'a str>,
+    pub date_created: &'a str,
+    pub date_updated: &'a str,
+}
+
+// Per user overrides of the configured spending limits, a missing value falls back to the configured default.
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct SpendingLimit {
+    pub matrix_id: String,
+    pub max_payment_amount: Option<i64>,
+    pub max_daily_amount: Option<i64>,
+    pub date_created: String,
+    pub date_updated: String,
+}
+
+pub const VOUCHER_STATUS_OPEN: &str = "open";
+pub const VOUCHER_STATUS_REDEEMED: &str = "redeemed";
+pub const VOUCHER_STATUS_EXPIRED: &str = "expired";
+
+// The id is the one of the withdraw link in LNbits, expires_at is in RFC 3339.
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct Voucher {
+    pub id: String,
+    pub matrix_id: String,
+    pub amount: i64,
+    pub lnurl: String,
+    pub status: String,
+    pub expires_at: String,
+    pub date_created: String,
+    pub date_updated: String,
+    pub transaction_id: Option<String>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = vouchers)]
+pub struct NewVoucher {
+    pub id: String,
+    pub matrix_id: String,
+    pub amount: i64,
+    pub lnurl: String,
+    pub status: String,
+    pub expires_at: String,
+    pub date_created: String,
+    pub date_updated: String,
+    pub transaction_id: Option<String>,
+}
+
+// Matrix events that carried a command moving Sats, so a re-delivered event is not executed twice.
+#[derive(Insertable)]
+#[diesel(table_name = processed_events)]
+pub struct NewProcessedEvent {
+    pub event_id: String,
+    pub room_id: Option<String>,
+    pub date_created: String,
+}
@@ -8,3 +8,73 @@ diesel::table! {
         date_created -> Text,
     }
 }
+
+diesel::table! {
+    spending_limits (matrix_id) {
+        matrix_id -> Text,
+        max_payment_amount -> Nullable<BigInt>,
+        max_daily_amount -> Nullable<BigInt>,
+        date_created -> Text,
+        date_updated -> Text,
+    }
+}
+
+diesel::table! {
+    transactions (id) {
+        id -> Text,
+        kind -> Text,
+        sender -> Nullable<Text>,
+        recipient -> Nullable<Text>,
+        amount -> BigInt,
+        memo -> Nullable<Text>,
+        payment_hash -> Nullable<Text>,
+        bolt11 -> Nullable<Text>,
+        room_id -> Nullable<Text>,
+        event_id -> Nullable<Text>,
+        status -> Text,
+        date_created -> Text,
+        date_updated -> Text,
+        created_at -> BigInt,
+    }
+}
+
+diesel::table! {
+    notification_settings (matrix_id) {
+        matrix_id -> Text,
+        dm_notifications -> Bool,
+        dm_room_id -> Nullable<Text>,
+        date_created -> Text,
+        date_updated -> Text,
+    }
+}
+
+diesel::table! {
+    vouchers (id) {
+        id -> Text,
+        matrix_id -> Text,
+        amount -> BigInt,
+        lnurl -> Text,
+        status -> Text,
+        expires_at -> Text,
+        date_created -> Text,
+        date_updated -> Text,
+        transaction_id -> Nullable<Text>,
+    }
+}
+
+diesel::table! {
+    processed_events (event_id) {
+        event_id -> Text,
+        room_id -> Nullable<Text>,
+        date_created -> Text,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(
+    matrix_id_2_lnbits_id,
+    notification_settings,
+    processed_events,
+    spending_limits,
+    transactions,
+    vouchers,
+);
@@ -0,0 +1,252 @@
+pub mod error {
+
+    use std::fmt;
+    use reqwest::StatusCode;
+    use simple_error::SimpleError;
+
+    #[derive(Debug)]
+    pub enum BotError {
+        InsufficientBalance,
+        InvoiceExpired,
+        InvalidInvoice(String),
+        WrongNetwork { invoice_network: String, network: String },
+        SelfPayment,
+        AmountMismatch { invoice_amount: u64, amount: u64 },
+        PaymentFailed(String),
+        PaymentPending,
+        UnknownRecipient(String),
+        InvalidLnurl(String),
+        InvalidCommand(String),
+        LNBitsUnreachable(String),
+        LNBitsError(String),
+        RateLimited,
+        PaymentLimitExceeded(u64),
+        NothingToConfirm,
+        AlreadyProcessed,
+        DailyLimitExceeded { limit: u64, remaining: u64 },
+        UnverifiedDevice(String),
+        ExchangeRateUnavailable(String),
+        Database(String),
+        Internal(String),
+    }
+
+    impl BotError {
+
+        /// Maps a failed LNbits call onto the case the user can act upon.
+        pub fn from_lnbits_failure(status: StatusCode, detail: &str) -> BotError {
+            let lowercase_detail = detail.to_lowercase();
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                BotError::RateLimited
+            } else if lowercase_detail.contains("insufficient balance") {
+                BotError::InsufficientBalance
+            } else if lowercase_detail.contains("expired") {
+                BotError::InvoiceExpired
+            } else if lowercase_detail.contains("bolt11") || lowercase_detail.contains("invalid invoice") {
+                BotError::InvalidInvoice(detail.to_string())
+            } else if status.is_server_error() && detail.is_empty() {
+                BotError::LNBitsUnreachable(status.to_string())
+            } else {
+                BotError::LNBitsError(if detail.is_empty() { status.to_string() } else { detail.to_string() })
+            }
+        }
+
+        /// True if the command did not get far enough to move any Sats, so executing it again is safe.
+        pub fn is_retryable(&self) -> bool {
+            matches!(self, BotError::LNBitsUnreachable(_) | BotError::RateLimited)
+        }
+
+        /// The reply we give back to the user in the room, telling them what they can do about it.
+        pub fn user_message(&self) -> String {
+            match self {
+                BotError::InsufficientBalance => {
+                    "You do not have enough Sats for this. Check your balance with !balance or top up with !invoice.".to_string()
+                },
+                BotError::InvoiceExpired => {
+                    "That invoice has expired. Please ask for a new one.".to_string()
+                },
+                BotError::InvalidInvoice(reason) => {
+                    format!("That does not look like a valid lightning invoice ({}).", reason)
+                },
+                BotError::WrongNetwork { invoice_network, network } => {
+                    format!("That invoice is for {}, but I only pay invoices on {}.", invoice_network, network)
+                },
+                BotError::SelfPayment => {
+                    "That invoice was created by your own wallet, paying it would not move any Sats.".to_string()
+                },
+                BotError::AmountMismatch { invoice_amount, amount } => {
+                    format!("That invoice is for {} Sats, not {} Sats. Leave out the amount to pay the invoice as is.", invoice_amount, amount)
+                },
+                BotError::PaymentFailed(reason) => {
+                    format!("Your payment failed ({}).", reason)
+                },
+                BotError::PaymentPending => {
+                    "Your payment is still on its way, check !history later to see if it settled.".to_string()
+                },
+                BotError::UnknownRecipient(recipient) => {
+                    format!("I could not find {:?}. Please use <amount> <username>.\n \
+                             If usernames are ambiguous write them out in full. I.e. like @username:example-server.com.",
+                            recipient)
+                },
+                BotError::InvalidLnurl(reason) => {
+                    format!("I could not use that LNURL or lightning address ({}).", reason)
+                },
+                BotError::InvalidCommand(reason) => {
+                    format!("I did not understand that command ({}). Please use '!help' to list the commands. \
+                             Please write usernames in plain text", reason)
+                },
+                BotError::LNBitsUnreachable(_) => {
+                    "I can not reach my lightning backend right now, please try again later.".to_string()
+                },
+                BotError::LNBitsError(reason) => {
+                    format!("My lightning backend refused that request ({}).", reason)
+                },
+                BotError::RateLimited => {
+                    "I am receiving too many requests right now, please wait a moment and try again.".to_string()
+                },
+                BotError::PaymentLimitExceeded(limit) => {
+                    format!("This payment exceeds your limit of {} Sats per payment.", limit)
+                },
+                BotError::NothingToConfirm => {
+                    "There is no payment waiting for your confirmation, it may have expired. Please send the command again.".to_string()
+                },
+                BotError::AlreadyProcessed => {
+                    "I already handled this message, so I did not execute it again.".to_string()
+                },
+                BotError::DailyLimitExceeded { limit, remaining } => {
+                    format!("This payment would exceed your limit of {} Sats per 24 hours, \
+                             you can send up to {} more Sats right now.", limit, remaining)
+                },
+                BotError::UnverifiedDevice(reason) => {
+                    format!("I only move Sats for verified devices in encrypted rooms, but {}. \
+                             Please verify your session and try again.", reason)
+                },
+                BotError::ExchangeRateUnavailable(_) => {
+                    "I could not retrieve the exchange rate for that currency, please try again later.".to_string()
+                },
+                BotError::Database(_) | BotError::Internal(_) => {
+                    "I seem to be experiencing a problem please try again later".to_string()
+                }
+            }
+        }
+    }
+
+    impl fmt::Display for BotError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                BotError::InsufficientBalance => write!(f, "Insufficient balance"),
+                BotError::InvoiceExpired => write!(f, "Invoice expired"),
+                BotError::InvalidInvoice(reason) => write!(f, "Invalid invoice: {}", reason),
+                BotError::WrongNetwork { invoice_network, network } => write!(f, "Invoice for {} while running on {}", invoice_network, network),
+                BotError::SelfPayment => write!(f, "Invoice belongs to the payer"),
+                BotError::AmountMismatch { invoice_amount, amount } => write!(f, "Invoice amount {} does not match {}", invoice_amount, amount),
+                BotError::PaymentFailed(reason) => write!(f, "Payment failed: {}", reason),
+                BotError::PaymentPending => write!(f, "Payment pending"),
+                BotError::UnknownRecipient(recipient) => write!(f, "Unknown recipient: {}", recipient),
+                BotError::InvalidLnurl(reason) => write!(f, "Invalid LNURL: {}", reason),
+                BotError::InvalidCommand(reason) => write!(f, "Invalid command: {}", reason),
+                BotError::LNBitsUnreachable(reason) => write!(f, "LNbits unreachable: {}", reason),
+                BotError::LNBitsError(reason) => write!(f, "LNbits error: {}", reason),
+                BotError::RateLimited => write!(f, "Rate limited"),
+                BotError::PaymentLimitExceeded(limit) => write!(f, "Payment limit of {} exceeded", limit),
+                BotError::NothingToConfirm => write!(f, "Nothing to confirm"),
+                BotError::AlreadyProcessed => write!(f, "Event already processed"),
+                BotError::DailyLimitExceeded { limit, remaining } => write!(f, "Daily limit of {} exceeded, {} remaining", limit, remaining),
+                BotError::UnverifiedDevice(reason) => write!(f, "Unverified device: {}", reason),
+                BotError::ExchangeRateUnavailable(reason) => write!(f, "Exchange rate unavailable: {}", reason),
+                BotError::Database(reason) => write!(f, "Database error: {}", reason),
+                BotError::Internal(reason) => write!(f, "Internal error: {}", reason),
+            }
+        }
+    }
+
+    impl std::error::Error for BotError {}
+
+    impl From<reqwest::Error> for BotError {
+        fn from(error: reqwest::Error) -> Self {
+            if error.status() == Some(StatusCode::TOO_MANY_REQUESTS) {
+                BotError::RateLimited
+            } else if error.is_connect() || error.is_timeout() {
+                BotError::LNBitsUnreachable(error.to_string())
+            } else {
+                BotError::LNBitsError(error.to_string())
+            }
+        }
+    }
+
+    impl From<lnurl::Error> for BotError {
+        fn from(error: lnurl::Error) -> Self {
+            BotError::InvalidLnurl(error.to_string())
+        }
+    }
+
+    impl From<diesel::result::Error> for BotError {
+        fn from(error: diesel::result::Error) -> Self {
+            BotError::Database(error.to_string())
+        }
+    }
+
+    impl From<diesel::ConnectionError> for BotError {
+        fn from(error: diesel::ConnectionError) -> Self {
+            BotError::Database(error.to_string())
+        }
+    }
+
+    impl From<diesel::r2d2::PoolError> for BotError {
+        fn from(error: diesel::r2d2::PoolError) -> Self {
+            BotError::Database(error.to_string())
+        }
+    }
+
+    impl From<serde_json::Error> for BotError {
+        fn from(error: serde_json::Error) -> Self {
+            BotError::LNBitsError(format!("Unexpected response ({})", error))
+        }
+    }
+
+    impl From<SimpleError> for BotError {
+        fn from(error: SimpleError) -> Self {
+            BotError::Internal(error.to_string())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn lnbits_failures_map_to_what_the_user_can_act_upon() {
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::TOO_MANY_REQUESTS, "Insufficient balance"),
+                             BotError::RateLimited));
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::BAD_REQUEST, "Insufficient balance."),
+                             BotError::InsufficientBalance));
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::BAD_REQUEST, "Invoice expired"),
+                             BotError::InvoiceExpired));
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::BAD_REQUEST, "Failed to decode bolt11"),
+                             BotError::InvalidInvoice(_)));
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::BAD_REQUEST, "Invalid invoice"),
+                             BotError::InvalidInvoice(_)));
+            assert!(matches!(BotError::from_lnbits_failure(StatusCode::BAD_GATEWAY, ""),
+                             BotError::LNBitsUnreachable(_)));
+        }
+
+        #[test]
+        fn other_lnbits_failures_keep_their_reason() {
+            match BotError::from_lnbits_failure(StatusCode::INTERNAL_SERVER_ERROR, "Payment route not found") {
+                BotError::LNBitsError(reason) => assert_eq!(reason, "Payment route not found"),
+                error => panic!("unexpected {:?}", error)
+            }
+            match BotError::from_lnbits_failure(StatusCode::BAD_REQUEST, "") {
+                BotError::LNBitsError(reason) => assert_eq!(reason, StatusCode::BAD_REQUEST.to_string()),
+                error => panic!("unexpected {:?}", error)
+            }
+        }
+
+        #[test]
+        fn only_failures_before_any_payment_are_retryable() {
+            assert!(BotError::LNBitsUnreachable(String::new()).is_retryable());
+            assert!(BotError::RateLimited.is_retryable());
+            assert!(!BotError::PaymentPending.is_retryable());
+            assert!(!BotError::PaymentFailed(String::new()).is_retryable());
+        }
+    }
+}
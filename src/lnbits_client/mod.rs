@@ -7,6 +7,11 @@ pub mod lnbits_client {
     use serde::{Deserialize, Serialize};
     use uuid::Uuid;
     use crate::Config;
+    use crate::error::error::BotError;
+    use reqwest::StatusCode;
+
+    // Long enough for most routes to resolve, after that the payment is left pending and settled in the background
+    const PAYMENT_TIMEOUT: Duration = Duration::from_secs(60);
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct InvoiceParams {
@@ -17,7 +22,9 @@ pub mod lnbits_client {
         #[serde(skip_serializing_if = "Option::is_none")]
         pub webhook: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
-        pub description_hash: Option<String>
+        pub description_hash: Option<String>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        pub internal: bool
     }
 
     impl InvoiceParams {
@@ -28,8 +35,21 @@ pub mod lnbits_client {
                 memo: if memo.is_none() { Some(Uuid::new_v4().to_string()) } else { memo.clone() },
                 webhook: None,
                 description_hash: None, // TODO(AE): I've no idea why this should work?
+                internal: false
             }
         }
+
+        // Internal invoices are made up by LNbits without asking its funding source, only its own wallets can pay them
+        pub fn internal(mut self) -> InvoiceParams {
+            self.internal = true;
+            self
+        }
+
+        // LNURL-pay wallets check that the invoice commits to the metadata they were shown
+        pub fn with_description_hash(mut self, description_hash: Option<String>) -> InvoiceParams {
+            self.description_hash = description_hash;
+            self
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -48,10 +68,58 @@ pub mod lnbits_client {
     }
 
     #[derive(Debug, Deserialize, Serialize)]
-    pub struct TransferParams {
-        pub memo: String,
-        pub num_satoshis: i64,
-        pub dest_wallet_id: String
+    pub struct PaymentResponse {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub payment_hash: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub checking_id: Option<String>,
+        // LNbits reports failures as {"detail": ...}, either a string or a list of validation errors
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub detail: Option<serde_json::Value>,
+    }
+
+    #[derive(Debug)]
+    pub struct PaidPayment {
+        pub payment_hash: String,
+    }
+
+    // Parameters of the LNbits withdraw extension, amounts are in Sats
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WithdrawLinkParams {
+        pub title: String,
+        pub min_withdrawable: i64,
+        pub max_withdrawable: i64,
+        pub uses: i64,
+        pub wait_time: i64,
+        pub is_unique: bool
+    }
+
+    impl WithdrawLinkParams {
+        pub fn single_use(title: &str, amount: u64) -> WithdrawLinkParams {
+            WithdrawLinkParams {
+                title: title.to_string(),
+                min_withdrawable: amount as i64,
+                max_withdrawable: amount as i64,
+                uses: 1,
+                wait_time: 1,
+                is_unique: false
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct WithdrawLink {
+        pub id: String,
+        pub lnurl: String,
+        pub uses: i64,
+        #[serde(default)]
+        pub used: i64,
+    }
+
+    impl WithdrawLink {
+        pub fn is_used_up(&self) -> bool {
+            self.used >= self.uses
+        }
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -76,6 +144,56 @@ pub mod lnbits_client {
         pub balance: Option<u64>,
     }
 
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Payment {
+        pub payment_hash: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub checking_id: Option<String>,
+        #[serde(default)]
+        pub pending: bool,
+        // Millisatoshis, negative for outgoing payments
+        pub amount: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub fee: Option<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub memo: Option<String>,
+        // Older LNbits versions send a unix timestamp, newer ones an ISO 8601 string
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub time: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub bolt11: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub preimage: Option<String>,
+        // Only sent by newer LNbits versions: "pending", "success" or "failed"
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub status: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct PaymentStatus {
+        pub paid: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub preimage: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub details: Option<Payment>,
+    }
+
+    impl PaymentStatus {
+        pub fn is_failed(&self) -> bool {
+            if self.paid { return false }
+            match &self.details {
+                Some(details) => {
+                    details.status.as_deref() == Some("failed") || (details.status.is_none() && !details.pending)
+                },
+                None => false
+            }
+        }
+
+        pub fn fee_milli_satoshis(&self) -> i64 {
+            self.details.as_ref().and_then(|details| details.fee).unwrap_or(0).abs()
+        }
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
     pub struct WalletInfo {
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -137,7 +255,7 @@ pub mod lnbits_client {
         }
 
         pub async fn create_user_with_initial_wallet(&self,
-                                                     create_user_args: &CreateUserArgs) -> Result<LNBitsUser, reqwest::Error> {
+                                                     create_user_args: &CreateUserArgs) -> Result<LNBitsUser, BotError> {
 
             let response = reqwest::Client::new().post([self.url.as_str(), "/usermanager/api/v1/users"].join(""))
                 .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
@@ -146,12 +264,13 @@ pub mod lnbits_client {
                 .json(create_user_args)
                 .send()
                 .await?
+                .error_for_status()?
                 .json::<LNBitsUser>()
                 .await?;
             Ok(response)
         }
 
-        pub async fn wallet_info(&self, wallet: &Wallet) -> Result<WalletInfo, reqwest::Error> {
+        pub async fn wallet_info(&self, wallet: &Wallet) -> Result<WalletInfo, BotError> {
             let header: [(String, String); 3] =
                 [(String::from("Content-Type"), String::from("application/json")),
                     (String::from("Accept"), String::from("application/json")),
@@ -163,44 +282,39 @@ pub mod lnbits_client {
                 .header((header[2]).0.as_str(), (header[2]).1.as_str())
                 .send()
                 .await?
+                .error_for_status()?
                 /*.json::<WalletInfo>()
                 .await?*/;
 
-            log::info!("Received: {:?}", response);
-
             let response_text = response.text().await?;
 
-            log::info!("Received Txt: {:?}", response_text);
-
-            let response: WalletInfo = serde_json::from_str(response_text.as_str()).unwrap();
+            let response: WalletInfo = serde_json::from_str(response_text.as_str())?;
 
             Ok(response)
         }
 
-        pub async fn wallets(&self, user: &LNBitsUser) -> Result<Vec<Wallet>, reqwest::Error> {
+        pub async fn wallets(&self, user: &LNBitsUser) -> Result<Vec<Wallet>, BotError> {
             let response = reqwest::Client::new().get([self.url.as_str(), "/usermanager/api/v1/wallets/", &*(user.id)].join(""))
                 .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
                 .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
                 .header((self.header[2]).0.as_str(), (self.header[2]).1.as_str())
                 .send()
                 .await?
+                .error_for_status()?
                 /*.json::<Vec<Wallet>>()
                 .await?*/;
 
-            log::info!("Received: {:?}", response);
-
+            // Not logged, the response holds the admin key of every wallet
             let response_text = response.text().await?;
 
-            log::info!("Received Txt: {:?}", response_text);
-
-            let response: Vec<Wallet> = serde_json::from_str(response_text.as_str()).unwrap();
+            let response: Vec<Wallet> = serde_json::from_str(response_text.as_str())?;
 
             Ok(response)
         }
 
         pub async fn invoice(&self,
                              wallet: &Wallet,
-                             invoice_params: &InvoiceParams) -> Result<BitInvoice, reqwest::Error> {
+                             invoice_params: &InvoiceParams) -> Result<BitInvoice, BotError> {
             let response = reqwest::Client::new().post([self.url.as_str(), "/api/v1/payments"].join(""))
                 .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
                 .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
@@ -208,27 +322,161 @@ pub mod lnbits_client {
                 .json(&invoice_params)
                 .send()
                 .await?
+                .error_for_status()?
                 .json::<BitInvoice>()
                 .await?;
 
             Ok(response)
         }
 
+        pub async fn payments(&self,
+                              wallet: &Wallet,
+                              limit: u64) -> Result<Vec<Payment>, BotError> {
+            let response = reqwest::Client::new().get([self.url.as_str(),
+                                                            "/api/v1/payments?limit=",
+                                                            limit.to_string().as_str()].join(""))
+                .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
+                .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
+                .header((self.header[2]).0.as_str(), wallet.in_key.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Vec<Payment>>()
+                .await?;
+
+            Ok(response)
+        }
+
+        pub async fn payment_status(&self,
+                                    wallet: &Wallet,
+                                    payment_hash: &str) -> Result<PaymentStatus, BotError> {
+            let response = reqwest::Client::new().get([self.url.as_str(), "/api/v1/payments/", payment_hash].join(""))
+                .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
+                .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
+                .header((self.header[2]).0.as_str(), wallet.in_key.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<PaymentStatus>()
+                .await?;
+
+            Ok(response)
+        }
+
+        pub async fn create_withdraw_link(&self,
+                                          wallet: &Wallet,
+                                          withdraw_link_params: &WithdrawLinkParams) -> Result<WithdrawLink, BotError> {
+            let response = reqwest::Client::new().post([self.url.as_str(), "/withdraw/api/v1/links"].join(""))
+                .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
+                .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
+                .header((self.header[2]).0.as_str(), wallet.admin_key.clone())
+                .json(&withdraw_link_params)
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<WithdrawLink>()
+                .await?;
+
+            Ok(response)
+        }
+
+        pub async fn withdraw_link(&self,
+                                   wallet: &Wallet,
+                                   link_id: &str) -> Result<WithdrawLink, BotError> {
+            let response = reqwest::Client::new().get([self.url.as_str(), "/withdraw/api/v1/links/", link_id].join(""))
+                .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
+                .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
+                .header((self.header[2]).0.as_str(), wallet.in_key.clone())
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<WithdrawLink>()
+                .await?;
+
+            Ok(response)
+        }
+
+        pub async fn delete_withdraw_link(&self,
+                                          wallet: &Wallet,
+                                          link_id: &str) -> Result<(), BotError> {
+            reqwest::Client::new().delete([self.url.as_str(), "/withdraw/api/v1/links/", link_id].join(""))
+                .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
+                .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
+                .header((self.header[2]).0.as_str(), wallet.admin_key.clone())
+                .send()
+                .await?
+                .error_for_status()?;
+
+            Ok(())
+        }
+
         // AE: Funny how the telegram bot tries to put the answer of this into a BitInvoice, I wouldn't
         pub async fn pay(&self,
                          wallet: &Wallet,
-                         payment_params: &PaymentParams) -> Result<(), reqwest::Error> {
-            reqwest::Client::new().post([self.url.as_str(), "/api/v1/payments"].join(""))
+                         payment_params: &PaymentParams) -> Result<PaidPayment, BotError> {
+            let response = reqwest::Client::new().post([self.url.as_str(), "/api/v1/payments"].join(""))
                 .header((self.header[0]).0.as_str(), (self.header[0]).1.as_str())
                 .header((self.header[1]).0.as_str(), (self.header[1]).1.as_str())
                 .header((self.header[2]).0.as_str(), wallet.admin_key.clone())
-                .timeout(Duration::from_secs(3600))
+                .timeout(PAYMENT_TIMEOUT)
                 .json(&payment_params)
                 .send()
-                .await?
-                .text()
-                .await?;
-            Ok(())
+                .await
+                .map_err(payment_error)?;
+
+            let status = response.status();
+
+            let response_text = response.text().await.map_err(payment_error)?;
+
+            log::info!("Received Txt: {:?} ({:?})", response_text, status);
+
+            let payment_response = parse_payment_response(status, response_text.as_str()).map_err(payment_failure)?;
+
+            match payment_response.payment_hash {
+                Some(payment_hash) => Ok(PaidPayment { payment_hash }),
+                None => Err(payment_failure(BotError::from_lnbits_failure(status, "")))
+            }
+        }
+    }
+
+    // Only a failed connect means the payment never left, once the request is out a timeout
+    // does not tell us whether it went through
+    fn payment_error(error: reqwest::Error) -> BotError {
+        if error.is_connect() {
+            BotError::LNBitsUnreachable(error.to_string())
+        } else {
+            BotError::PaymentPending
+        }
+    }
+
+    // Whatever LNbits answered, paying again could pay twice. A gateway error without an answer from
+    // LNbits itself leaves the payment pending, the settler finds out whether it went through.
+    fn payment_failure(error: BotError) -> BotError {
+        match error {
+            BotError::LNBitsUnreachable(_) => BotError::PaymentPending,
+            BotError::RateLimited => BotError::PaymentFailed(String::from("my lightning backend is rate limiting me")),
+            error => error
+        }
+    }
+
+    fn parse_payment_response(status: StatusCode, response_text: &str) -> Result<PaymentResponse, BotError> {
+        let payment_response = serde_json::from_str::<PaymentResponse>(response_text);
+
+        match payment_response {
+            Ok(payment_response) if status.is_success() && payment_response.detail.is_none() => {
+                Ok(payment_response)
+            },
+            Ok(PaymentResponse { detail: Some(serde_json::Value::String(detail)), .. }) => {
+                Err(BotError::from_lnbits_failure(status, detail.as_str()))
+            },
+            Ok(PaymentResponse { detail: Some(detail), .. }) => {
+                Err(BotError::from_lnbits_failure(status, detail.to_string().as_str()))
+            },
+            _ => {
+                // Most likely the error page of a proxy in front of LNbits, the status is all the user needs to see
+                log::warn!("Unexpected payment response {:?} ({:?})", response_text, status);
+                Err(BotError::from_lnbits_failure(status, ""))
+            }
         }
     }
 
@@ -257,6 +505,112 @@ pub mod lnbits_client {
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ERROR_PAGE: &str = "<html><body><h1>400 Bad Request</h1></body></html>";
+
+        #[test]
+        fn error_pages_are_not_passed_on_to_the_user() {
+            let error = parse_payment_response(StatusCode::BAD_REQUEST, ERROR_PAGE).unwrap_err();
+            assert!(matches!(error, BotError::LNBitsError(_)));
+            assert!(!error.user_message().contains("<html>"), "{}", error.user_message());
+        }
+
+        #[test]
+        fn only_internal_invoices_ask_for_it() {
+            let invoice_params = serde_json::to_value(InvoiceParams::simple_new(1, &None)).unwrap();
+            assert!(invoice_params.get("internal").is_none());
+
+            let invoice_params = serde_json::to_value(InvoiceParams::simple_new(1, &None).internal()).unwrap();
+            assert_eq!(invoice_params["internal"], serde_json::Value::Bool(true));
+        }
+
+        #[test]
+        fn non_json_payment_responses_are_errors() {
+            assert!(parse_payment_response(StatusCode::OK, ERROR_PAGE).is_err());
+            assert!(parse_payment_response(StatusCode::OK, "").is_err());
+            assert!(parse_payment_response(StatusCode::CREATED, "OK").is_err());
+        }
+
+        // Answers every request with the same error page, like a proxy in front of a broken LNbits would
+        async fn serve_error_page(status: u16) -> LNBitsClient {
+            let app = axum::Router::new()
+                .fallback(move || async move { (axum::http::StatusCode::from_u16(status).unwrap(), ERROR_PAGE) });
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+
+            LNBitsClient {
+                url,
+                header: [(String::from("Content-Type"), String::from("application/json")),
+                    (String::from("Accept"), String::from("application/json")),
+                    (String::from("X-Api-Key"), String::from("key"))]
+            }
+        }
+
+        fn test_wallet() -> Wallet {
+            Wallet {
+                id: String::from("wallet"),
+                admin: None,
+                admin_key: String::from("admin"),
+                in_key: String::from("in"),
+                name: String::from("wallet"),
+                user: String::from("user"),
+                balance: None
+            }
+        }
+
+        fn test_user() -> LNBitsUser {
+            LNBitsUser {
+                id: String::from("user"),
+                name: String::from("user"),
+                email: String::new(),
+                admin: String::from("admin"),
+                password: None,
+                wallets: None
+            }
+        }
+
+        #[tokio::test]
+        async fn non_json_wallet_responses_are_errors() {
+            for status in [200, 502] {
+                let client = serve_error_page(status).await;
+
+                let error = client.wallet_info(&test_wallet()).await.unwrap_err();
+                assert!(matches!(error, BotError::LNBitsError(_)), "{:?}", error);
+                assert!(!error.user_message().contains("<html>"), "{}", error.user_message());
+
+                let error = client.wallets(&test_user()).await.unwrap_err();
+                assert!(matches!(error, BotError::LNBitsError(_)), "{:?}", error);
+                assert!(!error.user_message().contains("<html>"), "{}", error.user_message());
+            }
+        }
+
+        #[test]
+        fn answers_to_a_payment_never_allow_paying_again() {
+            let error = payment_failure(parse_payment_response(StatusCode::BAD_GATEWAY, ERROR_PAGE).unwrap_err());
+            assert!(matches!(error, BotError::PaymentPending));
+
+            let error = payment_failure(parse_payment_response(StatusCode::GATEWAY_TIMEOUT, "").unwrap_err());
+            assert!(matches!(error, BotError::PaymentPending));
+
+            let error = payment_failure(parse_payment_response(StatusCode::TOO_MANY_REQUESTS, "").unwrap_err());
+            assert!(matches!(error, BotError::PaymentFailed(_)));
+
+            let error = payment_failure(parse_payment_response(StatusCode::INTERNAL_SERVER_ERROR,
+                                                               r#"{"detail": "Payment failed: no route"}"#).unwrap_err());
+            assert!(!error.is_retryable());
+        }
+
+        #[test]
+        fn lnbits_details_are_passed_on() {
+            let error = parse_payment_response(StatusCode::BAD_REQUEST, r#"{"detail": "Insufficient balance."}"#).unwrap_err();
+            assert!(matches!(error, BotError::InsufficientBalance));
+        }
+    }
 }
 
 
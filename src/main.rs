@@ -7,10 +7,11 @@ mod lnbits_client;
 mod config;
 mod matrix_bot;
 mod data_layer;
+mod error;
 
 use log::LevelFilter;
 use crate::config::config::{config_from_cmd, Config};
-use crate::data_layer::data_layer::DataLayer;
+use crate::data_layer::data_layer::{ColumnCipher, DataLayer};
 
 use crate::lnbits_client::lnbits_client::LNBitsClient;
 use crate::matrix_bot::matrix_bot::MatrixBot;
@@ -30,7 +31,31 @@ async fn main() -> Result<(), SimpleError>  {
 
     log::info!("Starting up.");
 
-    let data_layer =  DataLayer::new(&config);
+    if config.generate_appservice_registration {
+        try_with!(MatrixBot::generate_appservice_registration(&config).await,
+                  "Appservice registration could not be generated");
+        return Ok(())
+    }
+
+    let data_layer = try_with!(DataLayer::new(&config),
+                               "Database could not be opened");
+
+    try_with!(data_layer.run_migrations().await,
+              "Database could not be migrated");
+
+    if let Some(new_encryption_key) = config.rotate_encryption_key.as_ref() {
+        let new_cipher = try_with!(ColumnCipher::from_base64(new_encryption_key.as_str()),
+                                   "New encryption key is invalid");
+        let rotated = try_with!(data_layer.rotate_encryption_key(new_cipher).await,
+                                "Encryption key could not be rotated");
+        log::info!("Re-encrypted {} rows, set --database-encryption-key to the new key before restarting.", rotated);
+        return Ok(())
+    }
+
+    if config.migrate_only {
+        log::info!("Migrations applied, exiting.");
+        return Ok(())
+    }
 
     let ln_client = LNBitsClient::new(&config);
 
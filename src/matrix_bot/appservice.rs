@@ -0,0 +1,216 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::put;
+use axum::{Json, Router};
+use matrix_sdk::ruma::UserId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use simple_error::{bail, try_with, SimpleError};
+use subtle::ConstantTimeEq;
+use tokio::sync::mpsc::Sender;
+use uuid::Uuid;
+
+const REGISTRATION_ID: &str = "matrix-lightning-tip-bot";
+const REMEMBERED_TRANSACTIONS: usize = 1024;
+
+/// The application service registration, shared between the homeserver and the bot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Registration {
+    pub id: String,
+    pub url: String,
+    pub as_token: String,
+    pub hs_token: String,
+    pub sender_localpart: String,
+    pub rate_limited: bool,
+    pub namespaces: Namespaces
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Namespaces {
+    #[serde(default)]
+    pub users: Vec<Namespace>,
+    #[serde(default)]
+    pub aliases: Vec<Namespace>,
+    #[serde(default)]
+    pub rooms: Vec<Namespace>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Namespace {
+    pub exclusive: bool,
+    pub regex: String
+}
+
+impl Registration {
+
+    // The namespace only claims the bot user itself, we don't puppet anyone.
+    pub fn generate(bot_user_id: &UserId, url: &str) -> Registration {
+        Registration {
+            id: REGISTRATION_ID.to_string(),
+            url: url.to_string(),
+            as_token: Uuid::new_v4().simple().to_string(),
+            hs_token: Uuid::new_v4().simple().to_string(),
+            sender_localpart: bot_user_id.localpart().to_string(),
+            rate_limited: false,
+            namespaces: Namespaces {
+                users: vec![Namespace { exclusive: true, regex: escape_regex(bot_user_id.as_str()) }],
+                aliases: vec![],
+                rooms: vec![]
+            }
+        }
+    }
+
+    pub async fn load(path: &str) -> Result<Registration, SimpleError> {
+        let content = try_with!(tokio::fs::read_to_string(path).await,
+                                "Could not read the appservice registration");
+        let registration = try_with!(serde_yaml::from_str(content.as_str()),
+                                     "Could not parse the appservice registration");
+        Ok(registration)
+    }
+
+    pub async fn save(&self, path: &str) -> Result<(), SimpleError> {
+        if tokio::fs::try_exists(path).await.unwrap_or(false) {
+            bail!("Refusing to overwrite the existing appservice registration {:?}", path);
+        }
+        let content = try_with!(serde_yaml::to_string(self),
+                                "Could not serialize the appservice registration");
+        try_with!(tokio::fs::write(path, content).await,
+                  "Could not write the appservice registration");
+        Ok(())
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('^');
+    for character in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped.push('$');
+    escaped
+}
+
+/// Receives the transactions the homeserver pushes to us and hands their events on one by one.
+#[derive(Clone)]
+pub struct AppserviceServer {
+    hs_token: String,
+    events: Sender<Value>,
+    seen_transactions: Arc<Mutex<(HashSet<String>, VecDeque<String>)>>
+}
+
+#[derive(Debug, Deserialize)]
+struct TransactionParams {
+    access_token: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+struct Transaction {
+    #[serde(default)]
+    events: Vec<Value>
+}
+
+#[derive(Debug, Serialize)]
+struct MatrixError {
+    errcode: String,
+    error: String
+}
+
+fn matrix_error(status_code: StatusCode, errcode: &str, error: &str) -> Response {
+    (status_code, Json(MatrixError { errcode: errcode.to_string(), error: error.to_string() })).into_response()
+}
+
+impl AppserviceServer {
+
+    pub fn new(registration: &Registration, events: Sender<Value>) -> AppserviceServer {
+        AppserviceServer {
+            hs_token: registration.hs_token.clone(),
+            events,
+            seen_transactions: Arc::new(Mutex::new((HashSet::new(), VecDeque::new())))
+        }
+    }
+
+    pub async fn serve(self, listen_address: String) {
+        log::info!("Serving appservice transactions on {} ..", listen_address);
+
+        let app = Router::new()
+            .route("/_matrix/app/v1/transactions/:txn_id", put(transaction))
+            .with_state(self);
+
+        let listener = match tokio::net::TcpListener::bind(listen_address.as_str()).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                log::error!("Could not listen on {:?} due to {:?}..", listen_address, error);
+                return
+            }
+        };
+
+        if let Err(error) = axum::serve(listener, app).await {
+            log::error!("Appservice server stopped due to {:?}..", error);
+        }
+    }
+
+    // Older homeservers pass the token as query parameter, newer ones in the authorization header.
+    fn is_authorized(&self, headers: &HeaderMap, params: &TransactionParams) -> Option<bool> {
+        let bearer_token = headers.get(header::AUTHORIZATION)
+                                  .and_then(|value| value.to_str().ok())
+                                  .and_then(|value| value.strip_prefix("Bearer "));
+        let token = bearer_token.or(params.access_token.as_deref())?;
+        // Constant time, so the response time doesn't tell how much of a guessed token was right
+        Some(bool::from(token.as_bytes().ct_eq(self.hs_token.as_bytes())))
+    }
+
+    // The homeserver retries a transaction until it got an answer, those we already handled are skipped.
+    fn is_known_transaction(&self, txn_id: &str) -> bool {
+        self.seen_transactions.lock().unwrap().0.contains(txn_id)
+    }
+
+    fn remember_transaction(&self, txn_id: &str) {
+        let mut seen_transactions = self.seen_transactions.lock().unwrap();
+        let (ids, order) = &mut *seen_transactions;
+        if !ids.insert(txn_id.to_string()) {
+            return
+        }
+        order.push_back(txn_id.to_string());
+        if order.len() > REMEMBERED_TRANSACTIONS {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+    }
+}
+
+async fn transaction(State(server): State<AppserviceServer>,
+                     Path(txn_id): Path<String>,
+                     Query(params): Query<TransactionParams>,
+                     headers: HeaderMap,
+                     Json(transaction): Json<Transaction>) -> Response {
+    match server.is_authorized(&headers, &params) {
+        None => return matrix_error(StatusCode::UNAUTHORIZED, "M_UNAUTHORIZED", "Missing token"),
+        Some(false) => return matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "Invalid token"),
+        Some(true) => { }
+    }
+
+    if server.is_known_transaction(txn_id.as_str()) {
+        return Json(serde_json::json!({})).into_response()
+    }
+
+    log::info!("processing appservice transaction {:?} with {} events ..", txn_id, transaction.events.len());
+
+    for event in transaction.events {
+        if let Err(error) = server.events.send(event).await {
+            log::error!("Could not hand on appservice event due to {:?}..", error);
+            return matrix_error(StatusCode::INTERNAL_SERVER_ERROR, "M_UNKNOWN", "Event could not be processed")
+        }
+    }
+
+    // Only now, a transaction that failed half way is handed on again when the homeserver retries it
+    server.remember_transaction(txn_id.as_str());
+
+    Json(serde_json::json!({})).into_response()
+}
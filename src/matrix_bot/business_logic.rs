@@ -1,20 +1,40 @@
-use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use lnurl::LnUrlResponse;
-use simple_error::{bail, SimpleError, try_with};
 use uuid::Uuid;
 use qrcode_generator::QrCodeEcc;
+use tokio::time::Duration;
 use crate::{Config, DataLayer, LNBitsClient};
-use crate::data_layer::data_layer::NewMatrixId2LNBitsId;
-use crate::lnbits_client::lnbits_client::{CreateUserArgs, InvoiceParams, LNBitsUser, PaymentParams, Wallet, WalletInfo};
-use crate::matrix_bot::commands::{Command, CommandReply};
+use crate::error::error::BotError;
+use crate::data_layer::data_layer::{NewVoucher, VOUCHER_STATUS_EXPIRED, VOUCHER_STATUS_OPEN, VOUCHER_STATUS_REDEEMED};
+use crate::data_layer::data_layer::NewProcessedEvent;
+use crate::data_layer::data_layer::{NewMatrixId2LNBitsId, NewTransaction, Transaction, TRANSACTION_KIND_INVOICE, TRANSACTION_KIND_PAY, TRANSACTION_KIND_SEND, TRANSACTION_KIND_VOUCHER, TRANSACTION_STATUS_EXPIRED, TRANSACTION_STATUS_FAILED, TRANSACTION_STATUS_PENDING, TRANSACTION_STATUS_SETTLED};
+use crate::lnbits_client::lnbits_client::{CreateUserArgs, InvoiceParams, LNBitsUser, PaidPayment, Payment, PaymentParams, PaymentStatus, Wallet, WalletInfo, WithdrawLinkParams};
+use crate::matrix_bot::commands::{Command, CommandOrigin, CommandReply, PendingConfirmation};
 use crate::matrix_bot::matrix_bot::LNBitsId;
-use crate::matrix_bot::utils::parse_lnurl;
+use crate::matrix_bot::utils::{amount_in_sats_from_bolt11, bolt11_is_expired, currency_for_network, decode_bolt11, escape_html, format_payment_time, network_for_currency, parse_lnurl, payment_hash_from_bolt11, DecodedInvoice};
+
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(300);
+const VOUCHER_VALIDITY_HOURS: i64 = 24;
+
+// How long a remote LNURL service gets to answer each request.
+const LNURL_TIMEOUT_SECONDS: u64 = 30;
+
+// Every open lightning address invoice is checked by the settler until it is paid or expires.
+const MAX_PENDING_ADDRESS_INVOICES_PER_USER: i64 = 20;
+const MAX_PENDING_ADDRESS_INVOICES: i64 = 500;
+
+// LNbits keeps this much aside for routing fees when paying external invoices.
+const RESERVE_FEE_MIN_SATS: u64 = 2;
+const RESERVE_FEE_PERCENT: u64 = 1;
 
 #[derive(Clone)]
 pub struct BusinessLogicContext  {
     lnbits_client: LNBitsClient,
     data_layer: DataLayer,
-    config: Config
+    config: Config,
+    pending_confirmations: Arc<Mutex<HashMap<String, PendingConfirmation>>>
 }
 
 impl BusinessLogicContext {
@@ -25,7 +45,8 @@ impl BusinessLogicContext {
         BusinessLogicContext {
             lnbits_client,
             data_layer,
-            config: config.clone()
+            config: config.clone(),
+            pending_confirmations: Arc::new(Mutex::new(HashMap::new()))
         }
     }
 
@@ -35,12 +56,18 @@ impl BusinessLogicContext {
                  !balance - Check your balance: !balance\n\
                  !send    - Send funds to a user: !send <amount> <@user> or <@user:domain.com>, or a lightning address <lightning@address.com> [<memo>]\n\
                  !invoice - Receive over Lightning: !invoice <amount> [<memo>]\n\
-                 !pay     - Pay  over Lightning: !pay <invoice>\n\
+                 !pay     - Pay  over Lightning: !pay <invoice> [<amount>]\n\
+                 !decode  - Show what an invoice is for before paying it: !decode <invoice>\n\
+                 !withdraw-from - Claim Sats from an LNURL-withdraw, or just paste it: !withdraw-from <lnurl> [<amount>]\n\
+                 !voucher - Create a single use LNURL-withdraw voucher, anyone holding it can claim it: !voucher <amount>\n\
                  !help    - Read this help.\n\
                  !donate  - Donate to the matrix-lighting-tip-bot project: !donate <amount>\n\
                  !party   - Start a Party: !party\n\
                  !fiat-to-sats - Convert fiat to satoshis: !fiat-to-sats <amount> <currency (USD, EUR, CHF)>\n\
                  !sats-to-fiat - Convert satoshis to fiat: !sats-to-fiat <amount> <currency (USD, EUR, CHF)>\n\
+                 !history - List your most recent payments: !history [<number>]\n\
+                 !notify  - Get a direct message when you receive Sats: !notify <on|off>\n\
+                 !confirm - Confirm a large payment, or react to it with ✅: !confirm\n\
                  !version - Print the version of this bot\n\
                  If you wanna help consider donating, or sending some btc to :{:?}",
                  env!("CARGO_PKG_VERSION"),
@@ -48,86 +75,268 @@ impl BusinessLogicContext {
     }
 
     pub async fn processing_command(&self,
-                                command: Command) -> Result<CommandReply, SimpleError> {
+                                command: Command,
+                                origin: &CommandOrigin) -> Result<CommandReply, BotError> {
+        // Claimed before anything is executed, so a re-delivered event can't pay twice, even after a restart
+        let claimed = command.moves_money() && self.claim_event(origin).await?;
+
+        let result = self.confirm_or_execute_command(command, origin).await;
+
+        // Nothing moved yet, so let a re-delivery of the event try again
+        if let Err(error) = &result {
+            if claimed && error.is_retryable() {
+                self.release_event(origin).await;
+            }
+        }
+
+        result
+    }
+
+    async fn confirm_or_execute_command(&self,
+                                        command: Command,
+                                        origin: &CommandOrigin) -> Result<CommandReply, BotError> {
+        if let Command::Confirm { sender, prompt_event_id } = &command {
+            return self.do_process_confirm(sender.as_str(), prompt_event_id.as_deref(), origin).await
+        }
+
+        if let Some(confirmation_prompt) = self.confirmation_prompt(&command, origin) {
+            return Ok(confirmation_prompt)
+        }
+
+        self.execute_command(command, origin).await
+    }
+
+    // Returns false for commands without an event, which can't be re-delivered.
+    async fn claim_event(&self, origin: &CommandOrigin) -> Result<bool, BotError> {
+        let event_id = match &origin.event_id {
+            Some(event_id) => event_id.clone(),
+            None => return Ok(false)
+        };
+
+        let new_processed_event = NewProcessedEvent {
+            event_id: event_id.clone(),
+            room_id: origin.room_id.clone(),
+            date_created: Utc::now().to_string()
+        };
+        if !self.data_layer.claim_event_id(new_processed_event).await? {
+            log::warn!("Skipping event {:?} which was already processed", event_id);
+            return Err(BotError::AlreadyProcessed)
+        }
+
+        Ok(true)
+    }
+
+    async fn release_event(&self, origin: &CommandOrigin) {
+        if let Some(event_id) = &origin.event_id {
+            if let Err(error) = self.data_layer.release_event_id(event_id.as_str()).await {
+                log::warn!("Could not release event {:?} due to {:?}..", event_id, error);
+            }
+        }
+    }
+
+    async fn execute_command(&self,
+                             command: Command,
+                             origin: &CommandOrigin) -> Result<CommandReply, BotError> {
         let command_reply = match command {
             Command::Tip { sender, amount, memo, replyee } => {
-                try_with!(self.do_process_send(sender.as_str(),
+                self.do_process_send(sender.as_str(),
                                                replyee.as_str(),
                                                amount,
-                                               &memo).await,
-                                               "Could not process tip.")
+                                               &memo,
+                                               origin).await?
             },
             Command::Send { sender, amount, recipient, memo } => {
-                try_with!(self.do_process_send(sender.as_str(),
+                self.do_process_send(sender.as_str(),
                                                recipient.as_str(),
                                                amount,
-                                               &memo).await,
-                          "Could not process send.")
+                                               &memo,
+                                               origin).await?
             },
             Command::Invoice { sender, amount, memo } => {
-                try_with!(self.do_process_invoice(sender.as_str(),
+                self.do_process_invoice(sender.as_str(),
                                                   amount,
-                                                  &memo).await,
-                          "Could not process invoice")
+                                                  &memo,
+                                                  origin).await?
             },
             Command::Balance { sender } => {
-                try_with!(self.do_process_balance(sender.as_str()).await,
-                                                  "Could not process balance")
+                self.do_process_balance(sender.as_str()).await?
             },
-            Command::Pay { sender, invoice } => {
-                try_with!(self.do_process_pay(sender.as_str(), invoice.as_str()).await,
-                          "Could not process pay")
+            Command::Pay { sender, invoice, amount } => {
+                self.do_process_pay(sender.as_str(), invoice.as_str(), amount, origin).await?
             },
             Command::Help { } => {
-                try_with!(self.do_process_help().await,
-                          "Could not process help")
+                self.do_process_help().await?
             },
             Command::Donate { sender, amount } => {
-                try_with!(self.do_process_donate(sender.as_str(), amount).await,
-                         "Could not process donate")
+                self.do_process_donate(sender.as_str(), amount, origin).await?
             }
             Command::Party {  } => {
-                try_with!(self.do_process_party().await, "Could not process party")
+                self.do_process_party().await?
             },
             Command::Version { } => {
-                try_with!(self.do_process_version().await, "Could not process party")
+                self.do_process_version().await?
             },
             Command::FiatToSats { sender, amount, currency } => {
-                try_with!(self.do_process_fiat_conversion(sender.as_str(), amount, currency.as_str(), true).await,
-                      "Could not process FiatToSats")
+                self.do_process_fiat_conversion(sender.as_str(), amount, currency.as_str(), true).await?
             },
             Command::SatsToFiat { sender, amount, currency } => {
-                try_with!(self.do_process_fiat_conversion(sender.as_str(), amount as f64, currency.as_str(), false).await,
-                      "Could not process SatsToFiat")
+                self.do_process_fiat_conversion(sender.as_str(), amount as f64, currency.as_str(), false).await?
+            },
+            Command::History { sender, limit } => {
+                self.do_process_history(sender.as_str(), limit).await?
+            },
+            Command::Notify { sender, enabled } => {
+                self.do_process_notify(sender.as_str(), enabled).await?
+            },
+            Command::Decode { invoice } => {
+                self.do_process_decode(invoice.as_str()).await?
+            },
+            Command::WithdrawFrom { sender, lnurl, amount } => {
+                self.do_process_withdraw_from(sender.as_str(), lnurl.as_str(), amount, origin).await?
+            },
+            Command::Voucher { sender, amount } => {
+                self.do_process_voucher(sender.as_str(), amount, origin).await?
             },
             _ => {
                 log::error!("Encountered unsuported command {:?} ..", command);
-                bail!("Could not process: {:?}", command)
+                return Err(BotError::InvalidCommand(format!("{:?}", command)))
             }
         };
         Ok(command_reply)
     }
 
-    async fn get_fiat_to_btc_rate(&self, currency: &str) -> Result<f64, SimpleError> {
+    // Payments above the configured threshold are held back until the sender confirms the summary we reply with.
+    fn confirmation_prompt(&self, command: &Command, origin: &CommandOrigin) -> Option<CommandReply> {
+        let confirmation_threshold = self.config.confirmation_threshold?;
+
+        let (sender, summary) = match command {
+            Command::Tip { sender, amount, memo, replyee } if *amount > confirmation_threshold => {
+                (sender, self.send_summary(*amount, replyee.as_str(), memo))
+            },
+            Command::Send { sender, amount, recipient, memo } if *amount > confirmation_threshold => {
+                (sender, self.send_summary(*amount, recipient.as_str(), memo))
+            },
+            Command::Pay { sender, invoice, amount } if payment_amount(invoice, *amount) > confirmation_threshold => {
+                (sender, self.pay_summary(invoice.as_str(), payment_amount(invoice, *amount)))
+            },
+            _ => return None
+        };
+
+        log::info!("Asking {:?} to confirm a payment ..", sender);
+
+        let text = format!("{}\nReact with ✅ or reply !confirm within {} minutes to go ahead.",
+                           summary,
+                           CONFIRMATION_TIMEOUT.as_secs() / 60);
+        let pending_confirmation = PendingConfirmation::new(sender.as_str(),
+                                                            command.clone(),
+                                                            origin.clone(),
+                                                            CONFIRMATION_TIMEOUT);
+        Some(CommandReply::text_only(text.as_str()).awaiting_confirmation(pending_confirmation))
+    }
+
+    fn send_summary(&self, amount: u64, recipient: &str, memo: &Option<String>) -> String {
+        // Sends between users of this bot are internal to LNbits and don't pay any routing fees
+        let fee_estimate = if parse_lnurl(recipient).is_some() { fee_estimate(amount) } else { 0 };
+        let memo = match memo {
+            Some(memo) => format!(" with memo {:?}", memo),
+            None => String::new()
+        };
+        format!("Please confirm sending {} Sats to {}{}. Estimated fee: up to {} Sats.",
+                amount,
+                recipient,
+                memo,
+                fee_estimate)
+    }
+
+    fn pay_summary(&self, bolt11_invoice: &str, amount: u64) -> String {
+        match decode_bolt11(bolt11_invoice) {
+            Ok(decoded_invoice) => format!("Please confirm paying {} Sats for this invoice. Estimated fee: up to {} Sats.\n{}",
+                                           amount,
+                                           fee_estimate(amount),
+                                           decoded_invoice.summary()),
+            Err(_) => format!("Please confirm paying {} Sats. Estimated fee: up to {} Sats.",
+                              amount,
+                              fee_estimate(amount))
+        }
+    }
+
+    pub fn await_confirmation(&self, prompt_event_id: &str, pending_confirmation: PendingConfirmation) {
+        let mut pending_confirmations = self.pending_confirmations.lock().unwrap();
+        pending_confirmations.retain(|_, pending_confirmation| !pending_confirmation.is_expired());
+        pending_confirmations.insert(prompt_event_id.to_string(), pending_confirmation);
+    }
+
+    pub fn is_awaiting_confirmation(&self, prompt_event_id: &str, sender: &str) -> bool {
+        let pending_confirmations = self.pending_confirmations.lock().unwrap();
+        match pending_confirmations.get(prompt_event_id) {
+            Some(pending_confirmation) => pending_confirmation.sender == sender && !pending_confirmation.is_expired(),
+            None => false
+        }
+    }
+
+    // Without a prompt to answer to, the most recent payment of the sender in the same room is confirmed.
+    fn take_pending_confirmation(&self,
+                                 sender: &str,
+                                 prompt_event_id: Option<&str>,
+                                 room_id: Option<&str>) -> Option<PendingConfirmation> {
+        let mut pending_confirmations = self.pending_confirmations.lock().unwrap();
+        pending_confirmations.retain(|_, pending_confirmation| !pending_confirmation.is_expired());
+
+        let prompt_event_id = match prompt_event_id {
+            Some(prompt_event_id) => prompt_event_id.to_string(),
+            None => pending_confirmations.iter()
+                                         .filter(|(_, pending_confirmation)| pending_confirmation.sender == sender
+                                                                             && pending_confirmation.origin.room_id.as_deref() == room_id)
+                                         .max_by_key(|(_, pending_confirmation)| pending_confirmation.expires_at)
+                                         .map(|(prompt_event_id, _)| prompt_event_id.clone())?
+        };
+
+        if pending_confirmations.get(&prompt_event_id)?.sender != sender {
+            return None
+        }
+        pending_confirmations.remove(&prompt_event_id)
+    }
+
+    async fn do_process_confirm(&self,
+                                sender: &str,
+                                prompt_event_id: Option<&str>,
+                                origin: &CommandOrigin) -> Result<CommandReply, BotError> {
+        log::info!("processing confirm command ..");
+
+        let pending_confirmation = self.take_pending_confirmation(sender,
+                                                                  prompt_event_id,
+                                                                  origin.room_id.as_deref())
+                                       .ok_or(BotError::NothingToConfirm)?;
+
+        self.execute_command(pending_confirmation.command, &pending_confirmation.origin).await
+    }
+
+    async fn get_fiat_to_btc_rate(&self, currency: &str) -> Result<f64, BotError> {
     let url = format!("https://api.coingecko.com/api/v3/simple/price?ids=bitcoin&vs_currencies={}", currency.to_lowercase());
     log::info!("Sending request to CoinGecko for currency: {}", currency);
 
     let response = reqwest::get(&url).await;
     if let Err(err) = &response {
         log::error!("Error while sending request to CoinGecko: {}", err);
-        return Err(SimpleError::new(err.to_string()));
+        return Err(BotError::ExchangeRateUnavailable(err.to_string()));
+    }
+
+    let response = response.unwrap();
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        log::error!("CoinGecko is rate limiting us");
+        return Err(BotError::RateLimited);
     }
 
-    let json = response.unwrap().json::<serde_json::Value>().await;
+    let json = response.json::<serde_json::Value>().await;
     if let Err(err) = &json {
         log::error!("Error while parsing response JSON: {}", err);
-        return Err(SimpleError::new(err.to_string()));
+        return Err(BotError::ExchangeRateUnavailable(err.to_string()));
     }
 
     let rate = json.unwrap()["bitcoin"][currency.to_lowercase()].as_f64().unwrap_or(0.0);
     if rate == 0.0 {
         log::error!("Received invalid rate from CoinGecko for currency: {}", currency);
-        return Err(SimpleError::new("Invalid conversion rate"));
+        return Err(BotError::ExchangeRateUnavailable(format!("Invalid conversion rate for {}", currency)));
     }
 
     log::info!("Received conversion rate: {} for currency: {}", rate, currency);
@@ -135,21 +344,21 @@ impl BusinessLogicContext {
     }
 
     // Fiat in Sats umrechnen
-    async fn convert_fiat_to_sats(&self, amount: f64, currency: &str) -> Result<u64, SimpleError> {
-        let rate = self.get_fiat_to_btc_rate(currency).await.map_err(|e| SimpleError::new(e.to_string()))?;
+    async fn convert_fiat_to_sats(&self, amount: f64, currency: &str) -> Result<u64, BotError> {
+        let rate = self.get_fiat_to_btc_rate(currency).await?;
         let sats = (amount / rate * 100_000_000.0) as u64;
         Ok(sats)
     }
 
     // Sats in Fiat umrechnen
-    async fn convert_sats_to_fiat(&self, amount: u64, currency: &str) -> Result<f64, SimpleError> {
-        let rate = self.get_fiat_to_btc_rate(currency).await.map_err(|e| SimpleError::new(e.to_string()))?;
+    async fn convert_sats_to_fiat(&self, amount: u64, currency: &str) -> Result<f64, BotError> {
+        let rate = self.get_fiat_to_btc_rate(currency).await?;
         let fiat = (amount as f64 / 100_000_000.0) * rate;
         Ok(fiat)
     }
 
     // Die Logik für das Verarbeiten der Fiat-Befehle
-    pub async fn do_process_fiat_conversion(&self, _sender: &str, amount: f64, currency: &str, is_fiat_to_sats: bool) -> Result<CommandReply, SimpleError> {
+    pub async fn do_process_fiat_conversion(&self, _sender: &str, amount: f64, currency: &str, is_fiat_to_sats: bool) -> Result<CommandReply, BotError> {
         let result = if is_fiat_to_sats {
             self.convert_fiat_to_sats(amount, currency).await.map(|sats| sats as f64)
         } else {
@@ -175,72 +384,401 @@ impl BusinessLogicContext {
                              sender: &str,
                              recipient: &str,
                              amount: u64,
-                             memo: &Option<String>) -> Result<CommandReply, SimpleError>  {
+                             memo: &Option<String>,
+                             origin: &CommandOrigin) -> Result<CommandReply, BotError>  {
         log::info!("processing send command ..");
 
+        self.check_spending_limits(sender, amount).await?;
+
     	// If it's an LNURL, pay to the external wallet, else handle it internally
         match parse_lnurl(recipient) {
             Some(lnurl) => {
                 let client = lnurl::Builder::default()
-                    .build_blocking().map_err(|e| SimpleError::from(e))?;
+                    .timeout(LNURL_TIMEOUT_SECONDS)
+                    .build_async()?;
 
-                let res = client.make_request(&lnurl.url).map_err(|e| SimpleError::from(e))?;
+                let res = client.make_request(&lnurl.url)
+                                .await
+                                .map_err(|e| BotError::UnknownRecipient(format!("{} ({})", recipient, e)))?;
 
                 match res {
                     LnUrlResponse::LnUrlPayResponse(pay) => {
                         // Convert sats to msats
-                        let res = client.get_invoice(&pay, amount * 1_000, None, match memo {
+                        let amount_msats = amount.checked_mul(1_000)
+                                                 .ok_or(BotError::InvalidCommand(String::from("Amount is too large")))?;
+                        let res = client.get_invoice(&pay, amount_msats, None, match memo {
                             Some(memo) => Some(memo.as_str()),
                             None => None,
-                        }).map_err(|e| SimpleError::from(e))?;
+                        }).await?;
+
+                        let transaction_id = self.record_outgoing_transaction(new_transaction(TRANSACTION_KIND_SEND,
+                                                                                              Some(sender),
+                                                                                              Some(recipient),
+                                                                                              amount,
+                                                                                              memo,
+                                                                                              Some(res.invoice()),
+                                                                                              origin)).await?;
 
-                        try_with!(self.pay_bolt11_invoice_as_matrix_is(sender, res.invoice()).await,
-                            "Could not pay invoice");
+                        // The payment may still be routing, the settler marks it settled once LNbits confirms it
+                        let result = self.pay_bolt11_invoice_as_matrix_is(sender, res.invoice(), None).await;
+                        if result.is_err() {
+                            self.finish_transaction(transaction_id.as_str(), &result).await;
+                        }
+                        result?;
+                    }
+                    LnUrlResponse::LnUrlWithdrawResponse(_) => {
+                        return Err(BotError::InvalidLnurl(String::from("That is an LNURL-withdraw, use !withdraw-from to claim it")));
                     }
                     _ => {
-                        return Err(SimpleError::new("Invalid LNURL"));
+                        return Err(BotError::InvalidLnurl(String::from("Only LNURL-pay is supported")));
                     }
                 }
             },
             None => {
-                let bolt11_invoice: String = try_with!(self.generate_bolt11_invoice_for_matrix_id(recipient, amount, memo).await,
-                                         "Could not generate invoice");
- 
-                try_with!(self.pay_bolt11_invoice_as_matrix_is(sender, bolt11_invoice.as_str()).await,
-                   "Could not pay invoice");
+                // Both parties have a wallet on our LNbits instance, which settles the invoice internally. An internal
+                // invoice keeps the funding source out of it entirely, a regular invoice is the fallback.
+                let bolt11_invoice = match self.generate_internal_bolt11_invoice_for_matrix_id(recipient, amount, memo).await {
+                    Ok(bolt11_invoice) => bolt11_invoice,
+                    Err(error) => {
+                        log::warn!("Could not create an internal invoice for {:?} due to {:?}, using a regular one ..", recipient, error);
+                        self.generate_bolt11_invoice_for_matrix_id(recipient, amount, memo, None).await?
+                    }
+                };
+
+                let transaction_id = self.record_outgoing_transaction(new_transaction(TRANSACTION_KIND_SEND,
+                                                                                      Some(sender),
+                                                                                      Some(recipient),
+                                                                                      amount,
+                                                                                      memo,
+                                                                                      Some(bolt11_invoice.as_str()),
+                                                                                      origin)).await?;
+
+                let result = self.pay_bolt11_invoice_as_matrix_is(sender, bolt11_invoice.as_str(), None).await;
+                self.finish_transaction(transaction_id.as_str(), &result).await;
+                result?;
             }
         }
  
-        if memo.is_some() {
-            Ok(CommandReply::text_only(format!("{:?} sent {:?} Sats to {:?} with memo {:?}",
-                                              sender,
-                                              amount,
-                                              recipient,
-                                              memo.clone().unwrap()).as_str()))
+        let command_reply = if memo.is_some() {
+            CommandReply::text_only(format!("{:?} sent {:?} Sats to {:?} with memo {:?}",
+                                            sender,
+                                            amount,
+                                            recipient,
+                                            memo.clone().unwrap()).as_str())
         }
         else {
-            Ok(CommandReply::text_only(format!("{:?} sent {:?} Sats to {:?}",
-                                              sender,
-                                              amount,
-                                              recipient).as_str()))
+            CommandReply::text_only(format!("{:?} sent {:?} Sats to {:?}",
+                                            sender,
+                                            amount,
+                                            recipient).as_str())
+        };
+
+        if parse_lnurl(recipient).is_some() || !self.wants_dm_notifications(recipient).await {
+            return Ok(command_reply)
+        }
+
+        let mut notification = format!("You received {} Sats from {}", amount, sender);
+        if let Some(memo) = memo {
+            notification.push_str(format!(" with memo {:?}", memo).as_str());
+        }
+        if let (Some(room_id), Some(event_id)) = (&origin.room_id, &origin.event_id) {
+            notification.push_str(format!(" https://matrix.to/#/{}/{}", room_id, event_id).as_str());
+        }
+
+        Ok(command_reply.notifying(recipient, notification.as_str()))
+    }
+
+    pub async fn wants_dm_notifications(&self, matrix_id: &str) -> bool {
+        match self.data_layer.notification_settings_for_matrix_id(matrix_id).await {
+            Ok(Some(notification_settings)) => notification_settings.dm_notifications,
+            Ok(None) => false,
+            Err(error) => {
+                log::warn!("Could not load notification settings of {:?} due to {:?}..", matrix_id, error);
+                false
+            }
+        }
+    }
+
+    pub async fn dm_room_for_matrix_id(&self, matrix_id: &str) -> Option<String> {
+        match self.data_layer.notification_settings_for_matrix_id(matrix_id).await {
+            Ok(Some(notification_settings)) => notification_settings.dm_room_id,
+            Ok(None) => None,
+            Err(error) => {
+                log::warn!("Could not load notification settings of {:?} due to {:?}..", matrix_id, error);
+                None
+            }
+        }
+    }
+
+    pub async fn store_dm_room_for_matrix_id(&self, matrix_id: &str, dm_room_id: &str) {
+        let result = self.data_layer.set_dm_room_id(matrix_id, dm_room_id, Utc::now().to_string().as_str()).await;
+        if let Err(error) = result {
+            log::warn!("Could not store direct message room of {:?} due to {:?}..", matrix_id, error);
+        }
+    }
+
+    async fn do_process_notify(&self, sender: &str, enabled: bool) -> Result<CommandReply, BotError> {
+        log::info!("processing notify command ..");
+
+        self.data_layer.set_dm_notifications(sender, enabled, Utc::now().to_string().as_str()).await?;
+
+        if enabled {
+            Ok(CommandReply::text_only("I will send you a direct message whenever you receive Sats"))
+        } else {
+            Ok(CommandReply::text_only("I will no longer send you direct messages when you receive Sats"))
+        }
+    }
+
+    async fn do_process_decode(&self, bolt11_invoice: &str) -> Result<CommandReply, BotError> {
+        log::info!("processing decode command ..");
+
+        let decoded_invoice = decode_bolt11(bolt11_invoice)?;
+
+        let mut text = decoded_invoice.summary();
+        if let Err(error) = self.check_network(&decoded_invoice) {
+            text.push_str(format!("\n{}", error.user_message()).as_str());
+        }
+
+        Ok(CommandReply::text_only(text.as_str()))
+    }
+
+    // Creates an invoice in the users wallet and hands it to the LNURL-withdraw service to pay,
+    // the invoice watcher lets the user know once the Sats arrived.
+    async fn do_process_withdraw_from(&self,
+                                      sender: &str,
+                                      lnurl_text: &str,
+                                      amount: Option<u64>,
+                                      origin: &CommandOrigin) -> Result<CommandReply, BotError> {
+        log::info!("processing withdraw-from command ..");
+
+        let lnurl = parse_lnurl(lnurl_text).ok_or(BotError::InvalidLnurl(String::from("Could not parse the LNURL")))?;
+
+        let client = lnurl::Builder::default()
+            .timeout(LNURL_TIMEOUT_SECONDS)
+            .build_async()?;
+
+        let withdrawal = match client.make_request(&lnurl.url).await? {
+            LnUrlResponse::LnUrlWithdrawResponse(withdrawal) => withdrawal,
+            LnUrlResponse::LnUrlPayResponse(_) => {
+                return Err(BotError::InvalidLnurl(String::from("That is an LNURL-pay, use !send <amount> <lnurl> to pay it")))
+            },
+            _ => {
+                return Err(BotError::InvalidLnurl(String::from("Only LNURL-withdraw can be claimed")))
+            }
+        };
+
+        let max_amount = withdrawal.max_withdrawable / 1000;
+        let min_amount = withdrawal.min_withdrawable.unwrap_or(0).div_ceil(1000);
+        let amount = amount.unwrap_or(max_amount);
+        if amount == 0 || amount < min_amount || amount > max_amount {
+            return Err(BotError::InvalidCommand(format!("This LNURL allows withdrawing between {} and {} Sats",
+                                                        min_amount.max(1),
+                                                        max_amount)))
+        }
+
+        let memo = if withdrawal.default_description.is_empty() { None }
+                   else { Some(withdrawal.default_description.clone()) };
+
+        let bolt11_invoice = self.generate_bolt11_invoice_for_matrix_id(sender, amount, &memo, None).await?;
+
+        let transaction_id = self.record_transaction(new_transaction(TRANSACTION_KIND_INVOICE,
+                                                                     None,
+                                                                     Some(sender),
+                                                                     amount,
+                                                                     &memo,
+                                                                     Some(bolt11_invoice.as_str()),
+                                                                     origin)).await?;
+
+        match client.do_withdrawal(&withdrawal, bolt11_invoice.as_str()).await {
+            Ok(lnurl::Response::Ok { .. }) => {
+                Ok(CommandReply::text_only(format!("{:?} requested a withdrawal of {} Sats, I will let you know once they arrive",
+                                                   sender,
+                                                   amount).as_str()))
+            },
+            Ok(lnurl::Response::Error { reason }) => {
+                self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_FAILED).await;
+                Err(BotError::InvalidLnurl(reason))
+            },
+            Err(error) => {
+                self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_FAILED).await;
+                Err(BotError::from(error))
+            }
+        }
+    }
+
+    async fn do_process_voucher(&self,
+                                sender: &str,
+                                amount: u64,
+                                origin: &CommandOrigin) -> Result<CommandReply, BotError> {
+        log::info!("processing voucher command ..");
+
+        self.check_spending_limits(sender, amount).await?;
+
+        let wallet = self.wallet_for_matrix_id(sender).await?;
+
+        // LNbits doesn't reserve the funds for a withdraw link, so at least make sure they are there now
+        let balance = self.wallet2wallet_info(&wallet).await?.balance.unwrap_or(0) / 1000;
+        if balance < amount {
+            return Err(BotError::InsufficientBalance)
+        }
+
+        // The voucher counts against the spending limits from the moment it can be claimed
+        let transaction_id = self.record_outgoing_transaction(new_transaction(TRANSACTION_KIND_VOUCHER,
+                                                                              Some(sender),
+                                                                              None,
+                                                                              amount,
+                                                                              &None,
+                                                                              None,
+                                                                              origin)).await?;
+
+        let withdraw_link_params = WithdrawLinkParams::single_use(format!("Voucher from {}", sender).as_str(), amount);
+        let withdraw_link = self.lnbits_client.create_withdraw_link(&wallet, &withdraw_link_params).await;
+        if withdraw_link.is_err() {
+            self.finish_transaction(transaction_id.as_str(), &withdraw_link).await;
+        }
+        let withdraw_link = withdraw_link?;
+
+        let date_created = Utc::now();
+        let expires_at = date_created + ChronoDuration::hours(VOUCHER_VALIDITY_HOURS);
+
+        let new_voucher = NewVoucher {
+            id: withdraw_link.id.clone(),
+            matrix_id: sender.to_string(),
+            amount: amount as i64,
+            lnurl: withdraw_link.lnurl.clone(),
+            status: VOUCHER_STATUS_OPEN.to_string(),
+            expires_at: expires_at.to_rfc3339(),
+            date_created: date_created.to_string(),
+            date_updated: date_created.to_string(),
+            transaction_id: Some(transaction_id),
+        };
+        self.data_layer.insert_voucher(new_voucher).await?;
+
+        log::info!("Generated voucher {:?} for {:?} Sats", withdraw_link.id, amount);
+
+        let image: Vec<u8> = qrcode_generator::to_png_to_vec(withdraw_link.lnurl.as_str(),
+                                                              QrCodeEcc::Medium,
+                                                             256)
+                                       .map_err(|e| BotError::Internal(format!("Could not generate QR code {:?}", e)))?;
+
+        let text = format!("Voucher for {} Sats, valid until {}. It can be claimed once by anyone holding it.\n{}",
+                           amount,
+                           expires_at.format("%Y-%m-%d %H:%M UTC"),
+                           withdraw_link.lnurl);
+
+        Ok(CommandReply::new(text.as_str(), image))
+    }
+
+    /// Marks claimed vouchers as redeemed and removes the withdraw links of vouchers that expired unclaimed.
+    pub async fn settle_vouchers(&self) -> Result<(), BotError> {
+        let open_vouchers = self.data_layer.open_vouchers().await?;
+
+        for open_voucher in open_vouchers {
+            let wallet = match self.wallet_for_matrix_id(open_voucher.matrix_id.as_str()).await {
+                Ok(wallet) => wallet,
+                Err(error) => {
+                    log::warn!("Could not load wallet of {:?} due to {:?}..", open_voucher.matrix_id, error);
+                    continue
+                }
+            };
+
+            let withdraw_link = self.lnbits_client.withdraw_link(&wallet, open_voucher.id.as_str()).await;
+            if let Ok(withdraw_link) = &withdraw_link {
+                if withdraw_link.is_used_up() {
+                    log::info!("Voucher {:?} was redeemed ..", open_voucher.id);
+                    self.data_layer.update_voucher_status(open_voucher.id.as_str(),
+                                                          VOUCHER_STATUS_REDEEMED,
+                                                          Utc::now().to_string().as_str()).await?;
+                    if let Some(transaction_id) = &open_voucher.transaction_id {
+                        self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_SETTLED).await;
+                    }
+                    continue
+                }
+            }
+
+            let is_expired = DateTime::parse_from_rfc3339(open_voucher.expires_at.as_str())
+                                      .map(|expires_at| expires_at < Utc::now())
+                                      .unwrap_or(true);
+            if !is_expired {
+                continue
+            }
+
+            // Only give up on the voucher once LNbits no longer pays out on it
+            if withdraw_link.is_ok() {
+                if let Err(error) = self.lnbits_client.delete_withdraw_link(&wallet, open_voucher.id.as_str()).await {
+                    log::warn!("Could not remove withdraw link of voucher {:?} due to {:?}..", open_voucher.id, error);
+                    continue
+                }
+            }
+
+            log::info!("Voucher {:?} expired ..", open_voucher.id);
+            self.data_layer.update_voucher_status(open_voucher.id.as_str(),
+                                                  VOUCHER_STATUS_EXPIRED,
+                                                  Utc::now().to_string().as_str()).await?;
+            if let Some(transaction_id) = &open_voucher.transaction_id {
+                self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_EXPIRED).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn has_wallet(&self, matrix_id: &str) -> Result<bool, BotError> {
+        self.data_layer.lnbits_id_exists_for_matrix_id(matrix_id).await
+    }
+
+    /// Creates the invoice handed out for a payment to the lightning address of a user,
+    /// the comment of the payer ends up as memo and is forwarded once the invoice is paid.
+    pub async fn receive_via_lightning_address(&self,
+                                               matrix_id: &str,
+                                               amount: u64,
+                                               comment: Option<String>,
+                                               description_hash: String) -> Result<String, BotError> {
+        if self.data_layer.pending_external_invoice_count(Some(matrix_id)).await? >= MAX_PENDING_ADDRESS_INVOICES_PER_USER
+            || self.data_layer.pending_external_invoice_count(None).await? >= MAX_PENDING_ADDRESS_INVOICES {
+            log::warn!("Too many open lightning address invoices, refusing another one for {:?} ..", matrix_id);
+            return Err(BotError::RateLimited)
         }
+
+        let bolt11_invoice = self.generate_bolt11_invoice_for_matrix_id(matrix_id,
+                                                                        amount,
+                                                                        &comment,
+                                                                        Some(description_hash)).await?;
+
+        self.record_transaction(new_transaction(TRANSACTION_KIND_INVOICE,
+                                                None,
+                                                Some(matrix_id),
+                                                amount,
+                                                &comment,
+                                                Some(bolt11_invoice.as_str()),
+                                                &CommandOrigin::external())).await?;
+
+        Ok(bolt11_invoice)
     }
 
     async fn do_process_invoice(&self,
                                 sender: &str,
                                 amount: u64,
-                                memo: &Option<String>) -> Result<CommandReply, SimpleError> {
+                                memo: &Option<String>,
+                                origin: &CommandOrigin) -> Result<CommandReply, BotError> {
         log::info!("processing invoice command ..");
 
-        let bolt11_invoice: String = try_with!(self.generate_bolt11_invoice_for_matrix_id(sender, amount, memo).await,
-                                        "Could not generate invoice");
+        let bolt11_invoice: String = self.generate_bolt11_invoice_for_matrix_id(sender, amount, memo, None).await?;
+
+        self.record_transaction(new_transaction(TRANSACTION_KIND_INVOICE,
+                                                None,
+                                                Some(sender),
+                                                amount,
+                                                memo,
+                                                Some(bolt11_invoice.as_str()),
+                                                origin)).await?;
 
         log::info!("Generated {:?} as invoice", bolt11_invoice);
 
-        let image: Vec<u8> = try_with!(qrcode_generator::to_png_to_vec(bolt11_invoice.as_str(),
+        let image: Vec<u8> = qrcode_generator::to_png_to_vec(bolt11_invoice.as_str(),
                                                               QrCodeEcc::Medium,
-                                                             256),
-                                       "Could not generate QR code");
+                                                             256)
+                                       .map_err(|e| BotError::Internal(format!("Could not generate QR code {:?}", e)))?;
 
         // Insert QR code here
         let command_reply = CommandReply::new(bolt11_invoice.as_str(),
@@ -249,15 +787,12 @@ impl BusinessLogicContext {
         Ok(command_reply)
     }
 
-    async fn do_process_balance(&self, sender: &str) -> Result<CommandReply, SimpleError> {
+    async fn do_process_balance(&self, sender: &str) -> Result<CommandReply, BotError> {
         log::info!("processing balance command ..");
-        let lnbits_id = try_with!(self.matrix_id2lnbits_id(sender).await,
-                                  "Could not load client");
-        let wallet = try_with!(self.lnbits_id2wallet(&lnbits_id).await,
-                                      "Could not load wallet");
+        let lnbits_id = self.matrix_id2lnbits_id(sender).await?;
+        let wallet = self.lnbits_id2wallet(&lnbits_id).await?;
 
-        let wallet_info = try_with!(self.wallet2wallet_info(&wallet).await,
-                                    "Could not load wallet info");
+        let wallet_info = self.wallet2wallet_info(&wallet).await?;
 
         let balance = wallet_info.balance;
         let balance = if balance.is_none()  { 0 }
@@ -266,30 +801,245 @@ impl BusinessLogicContext {
         Ok(CommandReply::text_only(format!("Your balance is {} Sats", balance).as_str()))
     }
 
-    async fn do_process_pay(&self, sender: &str, bol11_invoice: &str) -> Result<CommandReply, SimpleError> {
+    async fn do_process_pay(&self,
+                            sender: &str,
+                            bol11_invoice: &str,
+                            amount: Option<u64>,
+                            origin: &CommandOrigin) -> Result<CommandReply, BotError> {
         log::info!("processing pay command ..");
 
-        try_with!(self.pay_bolt11_invoice_as_matrix_is(sender, bol11_invoice).await,
-                  "Could not pay invoice");
+        self.check_spending_limits(sender, payment_amount(bol11_invoice, amount)).await?;
 
-        Ok(CommandReply::text_only(format!("{:?} payed an invoice", sender).as_str()))
+        let transaction_id = self.record_outgoing_transaction(new_transaction(TRANSACTION_KIND_PAY,
+                                                                              Some(sender),
+                                                                              None,
+                                                                              payment_amount(bol11_invoice, amount),
+                                                                              &None,
+                                                                              Some(bol11_invoice),
+                                                                              origin)).await?;
+
+        let result = self.pay_bolt11_invoice_as_matrix_is(sender, bol11_invoice, amount).await;
+        if result.is_err() {
+            self.finish_transaction(transaction_id.as_str(), &result).await;
+        }
+        let paid_payment = result?;
+
+        // LNbits usually knows the outcome once the payment call returns, anything else is left to the settler
+        let payment_status = self.payment_status_for_matrix_id(sender, paid_payment.payment_hash.as_str()).await;
+
+        match payment_status {
+            Some(payment_status) if payment_status.paid => {
+                self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_SETTLED).await;
+                Ok(CommandReply::text_only(format!("{:?} payed an invoice. The payment settled with a fee of {} Sats, preimage {}",
+                                                   sender,
+                                                   payment_status.fee_milli_satoshis() as f64 / 1000.0,
+                                                   payment_status.preimage.clone().unwrap_or_default()).as_str()))
+            },
+            Some(payment_status) if payment_status.is_failed() => {
+                self.set_transaction_status(transaction_id.as_str(), TRANSACTION_STATUS_FAILED).await;
+                Err(BotError::PaymentFailed(String::from("the payment could not be routed")))
+            },
+            _ => {
+                Ok(CommandReply::text_only(format!("{:?} payed an invoice. The payment is still pending, check !history later to see if it settled",
+                                                   sender).as_str()))
+            }
+        }
     }
 
-    async fn do_process_help(&self) -> Result<CommandReply, SimpleError> {
+    /// Checks the invoices handed out with !invoice that are still open and returns the ones paid since the last check.
+    pub async fn settle_paid_invoices(&self) -> Result<Vec<Transaction>, BotError> {
+        let pending_invoices = self.data_layer.pending_transactions_of_kind(TRANSACTION_KIND_INVOICE).await?;
+
+        let mut paid_invoices = Vec::new();
+        for pending_invoice in pending_invoices {
+            let (recipient, payment_hash) = match (&pending_invoice.recipient, &pending_invoice.payment_hash) {
+                (Some(recipient), Some(payment_hash)) => (recipient.clone(), payment_hash.clone()),
+                _ => {
+                    log::warn!("Invoice {:?} can not be checked, marking it as failed ..", pending_invoice.id);
+                    self.set_transaction_status(pending_invoice.id.as_str(), TRANSACTION_STATUS_FAILED).await;
+                    continue
+                }
+            };
+
+            let wallet = match self.wallet_for_matrix_id(recipient.as_str()).await {
+                Ok(wallet) => wallet,
+                Err(error) => {
+                    log::warn!("Could not load wallet of {:?} due to {:?}..", recipient, error);
+                    continue
+                }
+            };
+
+            match self.lnbits_client.payment_status(&wallet, payment_hash.as_str()).await {
+                Ok(payment_status) if payment_status.paid => {
+                    // Another instance sharing the database may have seen the payment first, it sends the notification then
+                    let claimed = self.data_layer.claim_pending_transaction(pending_invoice.id.as_str(),
+                                                                            TRANSACTION_STATUS_SETTLED,
+                                                                            Utc::now().to_string().as_str()).await;
+                    match claimed {
+                        Ok(true) => {
+                            log::info!("Invoice {:?} got paid ..", pending_invoice.id);
+                            paid_invoices.push(pending_invoice);
+                        },
+                        Ok(false) => log::info!("Invoice {:?} was already settled elsewhere ..", pending_invoice.id),
+                        Err(error) => log::warn!("Could not settle invoice {:?} due to {:?}..", pending_invoice.id, error)
+                    }
+                },
+                Ok(_) => {
+                    if pending_invoice.bolt11.as_deref().is_none_or(bolt11_is_expired) {
+                        log::info!("Invoice {:?} expired unpaid ..", pending_invoice.id);
+                        self.set_transaction_status(pending_invoice.id.as_str(), TRANSACTION_STATUS_EXPIRED).await;
+                    }
+                },
+                Err(error) => {
+                    log::warn!("Could not check invoice {:?} due to {:?}..", pending_invoice.id, error);
+                }
+            }
+        }
+
+        Ok(paid_invoices)
+    }
+
+    /// Resolves sends and payments that were still in flight when the command replied.
+    pub async fn settle_outgoing_payments(&self) -> Result<Vec<(Transaction, PaymentStatus)>, BotError> {
+        let mut pending_payments = self.data_layer.pending_transactions_of_kind(TRANSACTION_KIND_SEND).await?;
+        pending_payments.extend(self.data_layer.pending_transactions_of_kind(TRANSACTION_KIND_PAY).await?);
+
+        let mut resolved_payments = Vec::new();
+        for pending_payment in pending_payments {
+            let (sender, payment_hash) = match (&pending_payment.sender, &pending_payment.payment_hash) {
+                (Some(sender), Some(payment_hash)) => (sender.clone(), payment_hash.clone()),
+                _ => continue
+            };
+
+            let payment_status = match self.payment_status_for_matrix_id(sender.as_str(), payment_hash.as_str()).await {
+                Some(payment_status) => payment_status,
+                None => continue
+            };
+            let new_status = if payment_status.paid {
+                TRANSACTION_STATUS_SETTLED
+            } else if payment_status.is_failed() {
+                TRANSACTION_STATUS_FAILED
+            } else {
+                continue
+            };
+
+            // Like for invoices, only the instance that resolves the payment tells the sender
+            let claimed = self.data_layer.claim_pending_transaction(pending_payment.id.as_str(),
+                                                                    new_status,
+                                                                    Utc::now().to_string().as_str()).await;
+            match claimed {
+                Ok(true) => {
+                    log::info!("Payment {:?} is {} ..", pending_payment.id, new_status);
+                    resolved_payments.push((pending_payment, payment_status));
+                },
+                Ok(false) => log::info!("Payment {:?} was already resolved elsewhere ..", pending_payment.id),
+                Err(error) => log::warn!("Could not resolve payment {:?} due to {:?}..", pending_payment.id, error)
+            }
+        }
+
+        Ok(resolved_payments)
+    }
+
+    async fn payment_status_for_matrix_id(&self, matrix_id: &str, payment_hash: &str) -> Option<PaymentStatus> {
+        let wallet = match self.wallet_for_matrix_id(matrix_id).await {
+            Ok(wallet) => wallet,
+            Err(error) => {
+                log::warn!("Could not load wallet to check payment {:?} due to {:?}..", payment_hash, error);
+                return None
+            }
+        };
+
+        match self.lnbits_client.payment_status(&wallet, payment_hash).await {
+            Ok(payment_status) => {
+                log::info!("Payment {:?} has status {:?} ..", payment_hash, payment_status);
+                Some(payment_status)
+            },
+            Err(error) => {
+                log::warn!("Could not check payment {:?} due to {:?}..", payment_hash, error);
+                None
+            }
+        }
+    }
+
+    async fn do_process_history(&self, sender: &str, limit: u64) -> Result<CommandReply, BotError> {
+        log::info!("processing history command ..");
+
+        let lnbits_id = self.matrix_id2lnbits_id(sender).await?;
+        let wallet = self.lnbits_id2wallet(&lnbits_id).await?;
+        let payments = self.lnbits_client.payments(&wallet, limit).await?;
+
+        if payments.is_empty() {
+            return Ok(CommandReply::text_only("You have not made or received any payments yet"))
+        }
+
+        // Usually covers the same period as the payments, older payments are looked up one by one
+        let ledger = self.data_layer.transactions_for_matrix_id(sender, limit as i64).await
+                                    .unwrap_or_else(|error| {
+                                        log::warn!("Could not load the ledger of {:?} due to {:?}..", sender, error);
+                                        Vec::new()
+                                    })
+                                    .into_iter()
+                                    .filter_map(|transaction| transaction.payment_hash.clone().map(|payment_hash| (payment_hash, transaction)))
+                                    .collect::<HashMap<String, Transaction>>();
+
+        let mut text = format!("Your last {} payments:\n", payments.len());
+        let mut html = String::from("<table><tr><th>Time</th><th>Amount</th><th>Counterparty</th><th>Memo</th></tr>");
+
+        for payment in payments.iter() {
+            let time = format_payment_time(&payment.time);
+            let amount = format!("{}{} Sats{}",
+                                 if payment.amount < 0 { "-" } else { "+" },
+                                 payment.amount.abs() / 1000, // Minisatashis are a bitch.
+                                 if payment.pending { " (pending)" } else { "" });
+            let counterparty = self.counterparty_for_payment(sender, payment, &ledger).await;
+            let memo = payment.memo.clone().unwrap_or_default();
+
+            text.push_str(format!("{} | {} | {} | {}\n", time, amount, counterparty, memo).as_str());
+            html.push_str(format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                                  escape_html(time.as_str()),
+                                  escape_html(amount.as_str()),
+                                  escape_html(counterparty.as_str()),
+                                  escape_html(memo.as_str())).as_str());
+        }
+        html.push_str("</table>");
+
+        Ok(CommandReply::with_html(text.as_str(), html.as_str()))
+    }
+
+    async fn counterparty_for_payment(&self,
+                                      matrix_id: &str,
+                                      payment: &Payment,
+                                      ledger: &HashMap<String, Transaction>) -> String {
+        let counterparty = match ledger.get(&payment.payment_hash) {
+            Some(transaction) => counterparty(matrix_id, transaction),
+            None => self.data_layer.transaction_for_payment_hash(payment.payment_hash.as_str()).await
+                                   .unwrap_or_else(|error| {
+                                       log::warn!("Could not look up transaction due to {:?}..", error);
+                                       None
+                                   })
+                                   .and_then(|transaction| counterparty(matrix_id, &transaction))
+        };
+        counterparty.unwrap_or(String::from("external"))
+    }
+
+    async fn do_process_help(&self) -> Result<CommandReply, BotError> {
         log::info!("processing help command ..");
         Ok(CommandReply::text_only(self.get_help_content().as_str()))
     }
 
-    async fn do_process_party(&self) -> Result<CommandReply, SimpleError> {
+    async fn do_process_party(&self) -> Result<CommandReply, BotError> {
         log::info!("processing party command ..");
         Ok(CommandReply::text_only("🎉🎊🥳 let's PARTY!! 🥳🎊🎉"))
     }
 
-    async fn do_process_version(&self) -> Result<CommandReply, SimpleError> {
+    async fn do_process_version(&self) -> Result<CommandReply, BotError> {
         Ok(CommandReply::text_only(format!("My version is {:?}", env!("CARGO_PKG_VERSION")).as_str()))
     }
 
-    async fn do_process_donate(&self, sender: &str,  amount: u64) -> Result<CommandReply, SimpleError> {
+    async fn do_process_donate(&self,
+                               sender: &str,
+                               amount: u64,
+                               origin: &CommandOrigin) -> Result<CommandReply, BotError> {
         if self.config.donate_user.is_none() {
             return Ok(CommandReply::text_only("Thanks but this agent does not accept donations"))
         }
@@ -298,7 +1048,8 @@ impl BusinessLogicContext {
             self.do_process_send(sender,
                                  self.config.donate_user.as_ref().unwrap().as_str(),
                                  amount,
-                                 &Some(format!("a generouse donation from {:?}", sender))).await;
+                                 &Some(format!("a generouse donation from {:?}", sender)),
+                                 origin).await;
         match result {
             Ok(_) => Ok(CommandReply::text_only("Thanks for the donation")),
             Err(error) => Err(error)
@@ -306,8 +1057,86 @@ impl BusinessLogicContext {
 
     }
 
-    async fn matrix_id2lnbits_id(&self, matrix_id: &str) -> Result<LNBitsId, SimpleError> {
-        if !(self.data_layer.lnbits_id_exists_for_matrix_id(matrix_id)) {
+    // Refuses a payment before anything is asked of LNbits or a remote wallet. It is checked again when the
+    // payment is recorded, by then other payments of the same sender may have been recorded too.
+    async fn check_spending_limits(&self, matrix_id: &str, amount: u64) -> Result<(), BotError> {
+        let (max_payment_amount, max_daily_amount) = self.spending_limits(matrix_id).await?;
+
+        let spent = if max_daily_amount.is_some() {
+            self.data_layer.outgoing_amounts_since(matrix_id, daily_limit_since())
+                           .await?
+                           .iter()
+                           .map(|amount| (*amount).max(0) as u64)
+                           .sum::<u64>()
+        } else {
+            0
+        };
+
+        let result = check_limits(amount, spent, max_payment_amount, max_daily_amount);
+        if let Err(error) = &result {
+            log::info!("Refusing payment of {:?} Sats by {:?}, already spent {:?} ({}) ..", amount, matrix_id, spent, error);
+        }
+        result
+    }
+
+    // The per user overrides, else the configured defaults, for a single payment and for 24 hours
+    async fn spending_limits(&self, matrix_id: &str) -> Result<(Option<u64>, Option<u64>), BotError> {
+        let spending_limit = self.data_layer.spending_limit_for_matrix_id(matrix_id).await?;
+
+        Ok((effective_limit(spending_limit.as_ref().and_then(|limit| limit.max_payment_amount),
+                            self.config.max_payment_amount),
+            effective_limit(spending_limit.as_ref().and_then(|limit| limit.max_daily_amount),
+                            self.config.max_daily_amount)))
+    }
+
+    // Checking the limits and recording the payment happen in one database transaction
+    async fn record_outgoing_transaction(&self, new_transaction: NewTransaction) -> Result<String, BotError> {
+        let matrix_id = new_transaction.sender.clone().unwrap_or_default();
+        let (max_payment_amount, max_daily_amount) = self.spending_limits(matrix_id.as_str()).await?;
+
+        let transaction_id = new_transaction.id.clone();
+        let amount = new_transaction.amount.max(0) as u64;
+
+        self.data_layer.insert_outgoing_transaction(new_transaction, daily_limit_since(), move |spent| {
+            let result = check_limits(amount, spent, max_payment_amount, max_daily_amount);
+            if let Err(error) = &result {
+                log::info!("Refusing payment of {:?} Sats by {:?}, already spent {:?} ({}) ..", amount, matrix_id, spent, error);
+            }
+            result
+        }).await?;
+
+        Ok(transaction_id)
+    }
+
+    async fn record_transaction(&self, new_transaction: NewTransaction) -> Result<String, BotError> {
+        let transaction_id = new_transaction.id.clone();
+
+        self.data_layer.insert_transaction(new_transaction).await?;
+
+        Ok(transaction_id)
+    }
+
+    // A pending payment stays pending, the background settler resolves it once LNbits knows the outcome
+    async fn finish_transaction<T>(&self, transaction_id: &str, result: &Result<T, BotError>) {
+        let status = match result {
+            Ok(_) => TRANSACTION_STATUS_SETTLED,
+            Err(BotError::PaymentPending) => return,
+            Err(_) => TRANSACTION_STATUS_FAILED
+        };
+        self.set_transaction_status(transaction_id, status).await;
+    }
+
+    async fn set_transaction_status(&self, transaction_id: &str, status: &str) {
+        let update = self.data_layer.update_transaction_status(transaction_id,
+                                                               status,
+                                                               Utc::now().to_string().as_str()).await;
+        if let Err(error) = update {
+            log::error!("Could not update transaction {:?} to {:?} due to {:?}..", transaction_id, status, error);
+        }
+    }
+
+    async fn matrix_id2lnbits_id(&self, matrix_id: &str) -> Result<LNBitsId, BotError> {
+        if !(self.data_layer.lnbits_id_exists_for_matrix_id(matrix_id).await?) {
 
             let wallet_name = matrix_id.to_owned() + "wallet";
             let admin_id = Uuid::new_v4().to_string();
@@ -323,82 +1152,335 @@ impl BusinessLogicContext {
             let result = self.lnbits_client.create_user_with_initial_wallet(&create_user_args).await;
             match  result {
                 Ok(result) => {
-                    log::info!("created {:?} ..", result);
+                    log::info!("created lnbits user {:?} for {:?} ..", result.id, matrix_id);
                     let date_created = Utc::now().to_string();
                     let new_matrix_id_2_lnbits_id = NewMatrixId2LNBitsId::new(matrix_id,
                                                                               result.id.as_str(),
                                                                               result.admin.as_str(),
                                                                               date_created.as_str());
-                    self.data_layer.insert_matrix_id_2_lnbits_id(new_matrix_id_2_lnbits_id);
+                    self.data_layer.insert_matrix_id_2_lnbits_id(new_matrix_id_2_lnbits_id).await?;
                 },
                 Err(e) => {
                     // Ask how this stuff works
-                    bail!("{:?}", e);
+                    return Err(e);
                 }
             }
         }
-        Ok(self.data_layer.lnbits_id_for_matrix_id(matrix_id))
+        self.data_layer.lnbits_id_for_matrix_id(matrix_id).await
     }
 
-    async fn lnbits_id2wallet(&self, lnbits_id: &LNBitsId) -> Result<Wallet, SimpleError> {
+    async fn wallet_for_matrix_id(&self, matrix_id: &str) -> Result<Wallet, BotError> {
+        let lnbits_id = self.matrix_id2lnbits_id(matrix_id).await?;
+        self.lnbits_id2wallet(&lnbits_id).await
+    }
+
+    async fn lnbits_id2wallet(&self, lnbits_id: &LNBitsId) -> Result<Wallet, BotError> {
         let lnbits_user = LNBitsUser::from_id(lnbits_id.lnbits_id.as_str());
-        let mut wallets = try_with!(self.lnbits_client.wallets(&lnbits_user).await,
-                                           "Could not retrieve wallets");
+        let mut wallets = self.lnbits_client.wallets(&lnbits_user).await?;
         if wallets.len() != 1 {
-            bail!("Expected a single wallet got {:?}", wallets)
+            return Err(BotError::Internal(format!("Expected a single wallet got {}", wallets.len())))
         }
         let wallet = wallets.remove(0);
 
         Ok(wallet)
     }
 
-    async fn wallet2wallet_info(&self, wallet: &Wallet) -> Result<WalletInfo, SimpleError> {
-        Ok(try_with!(self.lnbits_client.wallet_info(&wallet).await,
-                     "Could not retrieve wallet"))
+    async fn wallet2wallet_info(&self, wallet: &Wallet) -> Result<WalletInfo, BotError> {
+        self.lnbits_client.wallet_info(wallet).await
     }
 
     async fn pay_bolt11_invoice_as_matrix_is(&self,
                                              matrix_id: &str,
-                                             bolt11_invoice: &str) -> Result<(), SimpleError> {
+                                             bolt11_invoice: &str,
+                                             amount: Option<u64>) -> Result<PaidPayment, BotError> {
 
-        let parsed_invoice: lightning_invoice::Bolt11Invoice =
-            str::parse::<lightning_invoice::Bolt11Invoice>(bolt11_invoice).unwrap();
+        let decoded_invoice = decode_bolt11(bolt11_invoice)?;
 
-        if parsed_invoice.amount_milli_satoshis().is_none() {
-            bail!( "Incorrect invoice")
+        // LNbits refuses to pay invoices without an amount, an amount given with !pay only double checks the invoice
+        let invoice_amount = match (decoded_invoice.amount_in_sats, amount) {
+            (Some(invoice_amount), Some(amount)) if invoice_amount != amount => {
+                return Err(BotError::AmountMismatch { invoice_amount, amount })
+            },
+            (Some(invoice_amount), _) => invoice_amount,
+            (None, _) => {
+                return Err(BotError::InvalidInvoice(String::from("Invoice has no amount, please ask for one with an amount or use !send <amount> <lightning address>")))
+            }
+        };
+
+        if decoded_invoice.is_expired {
+            return Err(BotError::InvoiceExpired)
         }
-        let invoice_milli_satoshi_amount = parsed_invoice.amount_milli_satoshis().unwrap();
 
-        log::info!("Got an amount for {:?} satoshis ..", invoice_milli_satoshi_amount / 1000);
+        self.check_network(&decoded_invoice)?;
+
+        log::info!("Got an amount for {:?} satoshis ..", invoice_amount);
+
+        let lnbits_id = self.matrix_id2lnbits_id(matrix_id).await?;
 
-        let payment_params = PaymentParams::new(true, bolt11_invoice);
+        let wallet = self.lnbits_id2wallet(&lnbits_id).await?;
+
+        if self.is_own_invoice(&wallet, decoded_invoice.payment_hash.as_str()).await {
+            return Err(BotError::SelfPayment)
+        }
 
-        let lnbits_id = try_with!(self.matrix_id2lnbits_id(matrix_id).await,
-                                          "Could not get lnbits id");
+        let paid_payment = self.lnbits_client.pay(&wallet, &PaymentParams::new(true, bolt11_invoice)).await?;
 
-        let wallet = try_with!(self.lnbits_id2wallet(&lnbits_id).await,
-                                      "Could not get wallet");
+        log::info!("Paid invoice with payment hash {:?} ..", paid_payment.payment_hash);
 
-        try_with!(self.lnbits_client.pay(&wallet, &payment_params).await,
-                  "Could not perform payment");
+        Ok(paid_payment)
+    }
 
+    fn check_network(&self, decoded_invoice: &DecodedInvoice) -> Result<(), BotError> {
+        let currency = currency_for_network(self.config.network.as_str())
+                           .ok_or(BotError::Internal(format!("Unknown network {:?}", self.config.network)))?;
+        if decoded_invoice.currency != currency {
+            return Err(BotError::WrongNetwork { invoice_network: network_for_currency(&decoded_invoice.currency).to_string(),
+                                                network: self.config.network.clone() })
+        }
         Ok(())
     }
 
+    // LNbits only knows the payment hash for the wallet it was requested with, so an incoming
+    // payment here means the invoice was created by the payer's own wallet.
+    async fn is_own_invoice(&self, wallet: &Wallet, payment_hash: &str) -> bool {
+        match self.lnbits_client.payment_status(wallet, payment_hash).await {
+            Ok(payment_status) => payment_status.details
+                                                .map(|details| details.amount > 0)
+                                                .unwrap_or(false),
+            Err(error) => {
+                log::info!("Payment hash {:?} is not known to the payers wallet {:?} ..", payment_hash, error);
+                false
+            }
+        }
+    }
+
     async fn generate_bolt11_invoice_for_matrix_id(&self,
                                                    matrix_id: &str,
                                                    amount: u64,
-                                                   memo: &Option<String>) -> Result<String, SimpleError> {
+                                                   memo: &Option<String>,
+                                                   description_hash: Option<String>) -> Result<String, BotError> {
+
+        let invoice_params = InvoiceParams::simple_new(amount, memo).with_description_hash(description_hash);
+
+        self.invoice_for_matrix_id(matrix_id, &invoice_params).await
+    }
+
+    async fn generate_internal_bolt11_invoice_for_matrix_id(&self,
+                                                            matrix_id: &str,
+                                                            amount: u64,
+                                                            memo: &Option<String>) -> Result<String, BotError> {
+
+        let bolt11_invoice = self.invoice_for_matrix_id(matrix_id, &InvoiceParams::simple_new(amount, memo).internal()).await?;
+
+        // The fake wallet signing internal invoices does not necessarily use our network
+        self.check_network(&decode_bolt11(bolt11_invoice.as_str())?)?;
+
+        Ok(bolt11_invoice)
+    }
+
+    async fn invoice_for_matrix_id(&self,
+                                   matrix_id: &str,
+                                   invoice_params: &InvoiceParams) -> Result<String, BotError> {
 
-        let lnbits_id = try_with!(self.matrix_id2lnbits_id(matrix_id).await,
-                                  "Could not load client");
-        let wallet = try_with!(self.lnbits_id2wallet(&lnbits_id).await,
-                                      "Could not load wallet");
-        let invoice_params = InvoiceParams::simple_new(amount, memo);
+        let lnbits_id = self.matrix_id2lnbits_id(matrix_id).await?;
+        let wallet = self.lnbits_id2wallet(&lnbits_id).await?;
 
-        let invoice = try_with!(self.lnbits_client.invoice(&wallet, &invoice_params).await,
-                                   "Could not load invoice");
+        let invoice = self.lnbits_client.invoice(&wallet, invoice_params).await?;
 
         Ok(invoice.payment_request)
     }
 }
+
+// A per user override replaces the configured default, negative overrides block all payments.
+fn effective_limit(user_limit: Option<i64>, default_limit: Option<u64>) -> Option<u64> {
+    user_limit.map(|limit| limit.max(0) as u64).or(default_limit)
+}
+
+// spent is what the sender paid within the last 24 hours, before this payment.
+fn check_limits(amount: u64,
+                spent: u64,
+                max_payment_amount: Option<u64>,
+                max_daily_amount: Option<u64>) -> Result<(), BotError> {
+    if let Some(max_payment_amount) = max_payment_amount {
+        if amount > max_payment_amount {
+            return Err(BotError::PaymentLimitExceeded(max_payment_amount))
+        }
+    }
+
+    if let Some(max_daily_amount) = max_daily_amount {
+        if spent.saturating_add(amount) > max_daily_amount {
+            return Err(BotError::DailyLimitExceeded { limit: max_daily_amount,
+                                                      remaining: max_daily_amount.saturating_sub(spent) })
+        }
+    }
+
+    Ok(())
+}
+
+fn fee_estimate(amount: u64) -> u64 {
+    (amount.saturating_mul(RESERVE_FEE_PERCENT) / 100).max(RESERVE_FEE_MIN_SATS)
+}
+
+// Amountless invoices are paid with the amount given in the command.
+fn counterparty(matrix_id: &str, transaction: &Transaction) -> Option<String> {
+    if transaction.sender.as_deref() == Some(matrix_id) { transaction.recipient.clone() }
+    else { transaction.sender.clone() }
+}
+
+fn daily_limit_since() -> i64 {
+    (Utc::now() - ChronoDuration::hours(24)).timestamp()
+}
+
+fn new_transaction(kind: &str,
+                   sender: Option<&str>,
+                   recipient: Option<&str>,
+                   amount: u64,
+                   memo: &Option<String>,
+                   bolt11: Option<&str>,
+                   origin: &CommandOrigin) -> NewTransaction {
+    let now = Utc::now();
+    let date_created = now.to_string();
+
+    NewTransaction {
+        id: Uuid::new_v4().to_string(),
+        kind: kind.to_string(),
+        sender: sender.map(|sender| sender.to_string()),
+        recipient: recipient.map(|recipient| recipient.to_string()),
+        amount: amount as i64,
+        memo: memo.clone(),
+        payment_hash: bolt11.and_then(payment_hash_from_bolt11),
+        bolt11: bolt11.map(|bolt11| bolt11.to_string()),
+        room_id: origin.room_id.clone(),
+        event_id: origin.event_id.clone(),
+        status: TRANSACTION_STATUS_PENDING.to_string(),
+        date_created: date_created.clone(),
+        date_updated: date_created,
+        created_at: now.timestamp(),
+    }
+}
+
+fn payment_amount(bolt11_invoice: &str, amount: Option<u64>) -> u64 {
+    amount_in_sats_from_bolt11(bolt11_invoice).or(amount).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mainnet, 2500 uBTC, long expired
+    const COFFEE_INVOICE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+    // Mainnet, no amount, from the BOLT 11 test vectors
+    const AMOUNTLESS_INVOICE: &str = "lnbc1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq9qrsgq357wnc5r2ueh7ck6q93dj32dlqnls087fxdwk8qakdyafkq3yap9us6v52vjjsrvywa6rt52cm9r9zqt8r2t7mlcwspyetp5h2tztugp9lfyql";
+    const SENDER: &str = "@alice:example.com";
+
+    // Nothing in here is reachable, tests only get as far as the checks done before calling LNbits
+    fn test_config() -> Config {
+        Config {
+            matrix_server: String::from("http://127.0.0.1:1"),
+            matrix_username: String::from("@bot:example.com"),
+            matrix_auth_mode: String::from("password"),
+            matrix_password: None,
+            matrix_access_token: None,
+            matrix_device_id: None,
+            appservice_registration: None,
+            appservice_listen_address: None,
+            appservice_url: None,
+            generate_appservice_registration: false,
+            matrix_store_path: String::new(),
+            matrix_store_passphrase: None,
+            matrix_admin_users: vec![],
+            matrix_recovery_key: None,
+            store_recovery_key: false,
+            lnbits_url: String::from("http://127.0.0.1:1"),
+            lnbits_x_api_key: String::new(),
+            database_url: std::env::temp_dir().join(format!("mltb-test-{}.db", Uuid::new_v4())).to_str().unwrap().to_string(),
+            database_encryption_key: String::from("AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="),
+            rotate_encryption_key: None,
+            debug_level: String::from("Info"),
+            donate_user: None,
+            btc_donation_address: String::new(),
+            max_payment_amount: None,
+            max_daily_amount: None,
+            confirmation_threshold: None,
+            network: String::from("bitcoin"),
+            lnurl_listen_address: None,
+            lnurl_public_url: None,
+            lnurl_matrix_server_name: None,
+            require_verified_devices: false,
+            migrate_only: false
+        }
+    }
+
+    async fn test_context() -> BusinessLogicContext {
+        let config = test_config();
+        let data_layer = DataLayer::new(&config).unwrap();
+        data_layer.run_migrations().await.unwrap();
+        BusinessLogicContext::new(LNBitsClient::new(&config), data_layer, &config)
+    }
+
+    #[tokio::test]
+    async fn amount_given_with_pay_has_to_match_the_invoice() {
+        let context = test_context().await;
+
+        let result = context.pay_bolt11_invoice_as_matrix_is(SENDER, COFFEE_INVOICE, Some(1)).await;
+        assert!(matches!(result, Err(BotError::AmountMismatch { invoice_amount: 250000, amount: 1 })));
+
+        // The amounts match, so it gets as far as noticing the invoice expired
+        let result = context.pay_bolt11_invoice_as_matrix_is(SENDER, COFFEE_INVOICE, Some(250000)).await;
+        assert!(matches!(result, Err(BotError::InvoiceExpired)));
+    }
+
+    #[tokio::test]
+    async fn garbage_invoices_are_refused() {
+        let context = test_context().await;
+
+        for invoice in ["", "hello", "lnbc1notaninvoice", &COFFEE_INVOICE[..COFFEE_INVOICE.len() - 1]] {
+            let result = context.pay_bolt11_invoice_as_matrix_is(SENDER, invoice, None).await;
+            assert!(matches!(result, Err(BotError::InvalidInvoice(_))), "{:?}", invoice);
+        }
+    }
+
+    #[tokio::test]
+    async fn invoices_without_an_amount_are_refused() {
+        let context = test_context().await;
+
+        let result = context.pay_bolt11_invoice_as_matrix_is(SENDER, AMOUNTLESS_INVOICE, Some(100)).await;
+        assert!(matches!(result, Err(BotError::InvalidInvoice(_))));
+
+        let result = context.pay_bolt11_invoice_as_matrix_is(SENDER, AMOUNTLESS_INVOICE, None).await;
+        assert!(matches!(result, Err(BotError::InvalidInvoice(_))));
+    }
+
+    #[test]
+    fn user_limits_override_the_defaults() {
+        assert_eq!(effective_limit(Some(500), Some(1000)), Some(500));
+        assert_eq!(effective_limit(None, Some(1000)), Some(1000));
+        assert_eq!(effective_limit(Some(-1), Some(1000)), Some(0));
+        assert_eq!(effective_limit(None, None), None);
+    }
+
+    #[test]
+    fn payments_above_the_single_payment_limit_are_refused() {
+        assert!(check_limits(100, 0, Some(100), None).is_ok());
+        assert!(matches!(check_limits(101, 0, Some(100), None), Err(BotError::PaymentLimitExceeded(100))));
+        assert!(check_limits(u64::MAX, u64::MAX, None, None).is_ok());
+    }
+
+    #[test]
+    fn daily_limit_counts_what_was_already_spent() {
+        assert!(check_limits(400, 600, None, Some(1000)).is_ok());
+        assert!(matches!(check_limits(401, 600, None, Some(1000)),
+                         Err(BotError::DailyLimitExceeded { limit: 1000, remaining: 400 })));
+        assert!(matches!(check_limits(1, 1200, None, Some(1000)),
+                         Err(BotError::DailyLimitExceeded { limit: 1000, remaining: 0 })));
+        assert!(matches!(check_limits(u64::MAX, 1, None, Some(1000)),
+                         Err(BotError::DailyLimitExceeded { .. })));
+    }
+
+    #[test]
+    fn fee_reserve_has_a_minimum() {
+        assert_eq!(fee_estimate(0), RESERVE_FEE_MIN_SATS);
+        assert_eq!(fee_estimate(100), RESERVE_FEE_MIN_SATS);
+        assert_eq!(fee_estimate(10_000), 100);
+    }
+}
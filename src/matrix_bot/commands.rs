@@ -1,25 +1,57 @@
-use simple_error::{bail, SimpleError, try_with};
+use std::time::{Duration, Instant};
+use crate::error::error::BotError;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Command  {
     Tip     { sender: String, amount: u64, memo: Option<String>, replyee: String },
     Balance { sender: String },
     Send    { sender: String, amount: u64, recipient: String, memo: Option<String> },
     Invoice { sender: String, amount: u64, memo: Option<String> },
-    Pay     { sender: String, invoice: String },
+    Pay     { sender: String, invoice: String, amount: Option<u64> },
     Help    {  },
     Donate  { sender: String, amount: u64 },
     Party   { },
     Version { },
     FiatToSats { sender: String, amount: f64, currency: String },
     SatsToFiat { sender: String, amount: u64, currency: String },
+    History { sender: String, limit: u64 },
+    Notify  { sender: String, enabled: bool },
+    Confirm { sender: String, prompt_event_id: Option<String> },
+    Decode  { invoice: String },
+    WithdrawFrom { sender: String, lnurl: String, amount: Option<u64> },
+    Voucher { sender: String, amount: u64 },
     None,
 }
 
+#[derive(Debug, Clone)]
+pub struct CommandOrigin {
+    pub room_id: Option<String>,
+    pub event_id: Option<String>
+}
+
+#[derive(Debug)]
+pub struct DirectNotification {
+    pub recipient: String,
+    pub text: String
+}
+
+// A payment that is only executed once the sender confirms the prompt we replied with.
+// Held in memory only, so it does not survive a restart and is not shared between instances.
+#[derive(Debug)]
+pub struct PendingConfirmation {
+    pub sender: String,
+    pub command: Command,
+    pub origin: CommandOrigin,
+    pub expires_at: Instant
+}
+
 #[derive(Debug)]
 pub struct CommandReply {
     pub text: Option<String>,
-    pub image: Option<Vec<u8>>
+    pub html: Option<String>,
+    pub image: Option<Vec<u8>>,
+    pub notification: Option<DirectNotification>,
+    pub confirmation: Option<PendingConfirmation>
 }
 
 impl Command {
@@ -30,14 +62,25 @@ impl Command {
             _ => false
         }
     }
+
+    // Commands that take Sats out of the sender's wallet
+    pub fn moves_money(&self) -> bool {
+        matches!(self,
+                 Command::Tip { .. } |
+                 Command::Send { .. } |
+                 Command::Pay { .. } |
+                 Command::Donate { .. } |
+                 Command::Voucher { .. } |
+                 Command::Confirm { .. })
+    }
 }
 
-pub fn tip(sender:&str, text: &str, replyee: &str) -> Result<Command, SimpleError> {
+pub fn tip(sender:&str, text: &str, replyee: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 2 {
-        bail!("Expected a at least 2 arguments")
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
     }
-    let amount =   try_with!(split[1].parse::<u64>(), "could not parse value");
+    let amount =   split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?;
     let memo = if split.len() > 2 { Some(split[2..].join(" ") )  }
                             else { None };
     Ok(Command::Tip { sender: sender.to_string(),
@@ -46,18 +89,18 @@ pub fn tip(sender:&str, text: &str, replyee: &str) -> Result<Command, SimpleErro
                       memo })
 }
 
-pub fn balance(sender:&str)  -> Result<Command, SimpleError> {
+pub fn balance(sender:&str)  -> Result<Command, BotError> {
     Ok(Command::Balance { sender: String::from(sender) } )
 }
 
 pub fn send(sender:&str,
-            text: &str) -> Result<Command, SimpleError> {
+            text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
 
-    if split.len() < 2 {
-        bail!("Expected a at least 2 arguments")
+    if split.len() < 3 {
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 3 arguments")))
     }
-    let amount =  try_with!(split[1].parse::<u64>(), "could not parse value");
+    let amount =  split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?;
     let recipient = String::from(split[2]);
     let memo = if split.len() > 3 { Some(split[3..].join(" ") )  }
     else { None };
@@ -68,89 +111,318 @@ pub fn send(sender:&str,
 }
 
 pub fn invoice(sender:&str,
-               text: &str) -> Result<Command, SimpleError> {
+               text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 2 {
-        bail!("Expected a at least 2 arguments")
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
     }
-    let amount =  try_with!(split[1].parse::<u64>(), "could not parse value");
+    let amount =  split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?;
     let memo = if split.len() > 2 { Some(split[2..].join(" ") )  }
                             else { None };
     Ok(Command::Invoice { sender: String::from(sender), amount, memo })
 }
 
 pub fn pay(sender:&str,
-           text: &str) -> Result<Command, SimpleError> {
+           text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 2 {
-        bail!("Expected a at least 2 arguments")
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
     }
     let invoice = String::from(split[1]);
+    let amount = if split.len() > 2 { Some(split[2].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?) }
+                 else { None };
     Ok(Command::Pay { sender: String::from(sender),
-                      invoice })
+                      invoice,
+                      amount })
+}
+
+pub fn decode(text: &str) -> Result<Command, BotError> {
+    let split = text.split_whitespace().collect::<Vec<&str>>();
+    if split.len() < 2 {
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
+    }
+    Ok(Command::Decode { invoice: String::from(split[1]) })
+}
+
+pub fn withdraw_from(sender: &str, text: &str) -> Result<Command, BotError> {
+    let split = text.split_whitespace().collect::<Vec<&str>>();
+    if split.len() < 2 {
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
+    }
+    let lnurl = String::from(split[1].trim_start_matches("lightning:"));
+    let amount = if split.len() > 2 { Some(split[2].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?) }
+                 else { None };
+    Ok(Command::WithdrawFrom { sender: String::from(sender),
+                               lnurl,
+                               amount })
 }
 
-pub fn help() -> Result<Command, SimpleError> {
+pub fn voucher(sender: &str, text: &str) -> Result<Command, BotError> {
+    let split = text.split_whitespace().collect::<Vec<&str>>();
+    if split.len() < 2 {
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
+    }
+    let amount =  split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("could not parse value")))?;
+    if amount == 0 {
+        return Err(BotError::InvalidCommand(String::from("Expected an amount larger than 0")))
+    }
+    Ok(Command::Voucher { sender: String::from(sender), amount })
+}
+
+// A message consisting of just an LNURL is treated as a request to withdraw from it.
+pub fn pasted_lnurl(sender: &str, text: &str) -> Result<Command, BotError> {
+    let lnurl = text.split_whitespace().next().unwrap_or("").trim_start_matches("lightning:");
+    Ok(Command::WithdrawFrom { sender: String::from(sender),
+                               lnurl: String::from(lnurl),
+                               amount: None })
+}
+
+pub fn help() -> Result<Command, BotError> {
     Ok(Command::Help { })
 }
 
 
-pub fn donate(sender: &str, text: &str) -> Result<Command, SimpleError> {
+pub fn donate(sender: &str, text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 2 {
-        bail!("Expected a at least 2 arguments")
+        return Err(BotError::InvalidCommand(String::from("Expected a at least 2 arguments")))
     }
-    let amount =  try_with!(split[1].parse::<u64>(), "Could not parse value");
+    let amount =  split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("Could not parse value")))?;
     Ok(Command::Donate { sender: String::from(sender),
                          amount })
 }
 
-pub fn party() -> Result<Command, SimpleError> {
+pub fn party() -> Result<Command, BotError> {
     Ok(Command::Party {})
 }
 
-pub fn version() -> Result<Command, SimpleError> {
+pub fn version() -> Result<Command, BotError> {
     Ok(Command::Version { })
 }
 
-pub fn fiat_to_sats(sender: &str, text: &str) -> Result<Command, SimpleError> {
+pub fn fiat_to_sats(sender: &str, text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 3 {
-        bail!("Expected at least 3 arguments: !fiat-to-sats <amount> <currency>");
+        return Err(BotError::InvalidCommand(String::from("Expected at least 3 arguments: !fiat-to-sats <amount> <currency>")))
     }
-    let amount = try_with!(split[1].parse::<f64>(), "Could not parse amount");
+    let amount = split[1].parse::<f64>().map_err(|_| BotError::InvalidCommand(String::from("Could not parse amount")))?;
     let currency = split[2].to_string();
     Ok(Command::FiatToSats { sender: sender.to_string(), amount, currency })
 }
 
-pub fn sats_to_fiat(sender: &str, text: &str) -> Result<Command, SimpleError> {
+pub fn sats_to_fiat(sender: &str, text: &str) -> Result<Command, BotError> {
     let split = text.split_whitespace().collect::<Vec<&str>>();
     if split.len() < 3 {
-        bail!("Expected at least 3 arguments: !sats-to-fiat <amount> <currency>");
+        return Err(BotError::InvalidCommand(String::from("Expected at least 3 arguments: !sats-to-fiat <amount> <currency>")))
     }
-    let amount = try_with!(split[1].parse::<u64>(), "Could not parse amount");
+    let amount = split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("Could not parse amount")))?;
     let currency = split[2].to_string();
     Ok(Command::SatsToFiat { sender: sender.to_string(), amount, currency })
 }
 
+pub const DEFAULT_HISTORY_LIMIT: u64 = 10;
+pub const MAX_HISTORY_LIMIT: u64 = 100;
+
+pub fn history(sender: &str, text: &str) -> Result<Command, BotError> {
+    let split = text.split_whitespace().collect::<Vec<&str>>();
+    let limit = if split.len() > 1 { split[1].parse::<u64>().map_err(|_| BotError::InvalidCommand(String::from("Could not parse limit")))? }
+                else { DEFAULT_HISTORY_LIMIT };
+    if limit == 0 || limit > MAX_HISTORY_LIMIT {
+        return Err(BotError::InvalidCommand(format!("Expected a limit between 1 and {}", MAX_HISTORY_LIMIT)))
+    }
+    Ok(Command::History { sender: sender.to_string(), limit })
+}
+
+pub fn notify(sender: &str, text: &str) -> Result<Command, BotError> {
+    let split = text.split_whitespace().collect::<Vec<&str>>();
+    if split.len() < 2 {
+        return Err(BotError::InvalidCommand(String::from("Expected on or off")))
+    }
+    let enabled = match split[1] {
+        "on" => true,
+        "off" => false,
+        _ => return Err(BotError::InvalidCommand(String::from("Expected on or off")))
+    };
+    Ok(Command::Notify { sender: sender.to_string(), enabled })
+}
+
+pub fn confirm(sender: &str, prompt_event_id: Option<&str>) -> Result<Command, BotError> {
+    Ok(Command::Confirm { sender: sender.to_string(),
+                          prompt_event_id: prompt_event_id.map(|event_id| event_id.to_string()) })
+}
+
+impl CommandOrigin {
+
+    pub fn new(room_id: &str, event_id: &str) -> CommandOrigin {
+        CommandOrigin {
+            room_id: Some(room_id.to_string()),
+            event_id: Some(event_id.to_string())
+        }
+    }
+
+    // For requests that did not come in through a room, like lightning address payments.
+    pub fn external() -> CommandOrigin {
+        CommandOrigin {
+            room_id: None,
+            event_id: None
+        }
+    }
+}
+
+impl PendingConfirmation {
+
+    pub fn new(sender: &str, command: Command, origin: CommandOrigin, timeout: Duration) -> PendingConfirmation {
+        PendingConfirmation {
+            sender: sender.to_string(),
+            command,
+            origin,
+            expires_at: Instant::now() + timeout
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() > self.expires_at
+    }
+}
+
 impl CommandReply {
 
     pub fn text_only(text: &str) -> CommandReply {
         CommandReply {
             text: Some(text.to_string()),
-            image: None
+            html: None,
+            image: None,
+            notification: None,
+            confirmation: None
+        }
+    }
+
+    pub fn with_html(text: &str, html: &str) -> CommandReply {
+        CommandReply {
+            text: Some(text.to_string()),
+            html: Some(html.to_string()),
+            image: None,
+            notification: None,
+            confirmation: None
         }
     }
 
     pub fn new(text: &str, image: Vec<u8>) -> CommandReply {
         CommandReply {
             text: Some(text.to_string()),
-            image: Some(image)
+            html: None,
+            image: Some(image),
+            notification: None,
+            confirmation: None
         }
     }
 
+    pub fn notifying(mut self, recipient: &str, text: &str) -> CommandReply {
+        self.notification = Some(DirectNotification {
+            recipient: recipient.to_string(),
+            text: text.to_string()
+        });
+        self
+    }
+
+    pub fn awaiting_confirmation(mut self, confirmation: PendingConfirmation) -> CommandReply {
+        self.confirmation = Some(confirmation);
+        self
+    }
+
     pub fn is_empty(&self) -> bool {
         !self.text.is_some() && !self.image.is_some()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: &str = "@alice:example.com";
+
+    #[test]
+    fn tip_takes_amount_and_memo() {
+        let command = tip(SENDER, "!tip 21 thanks a lot", "@bob:example.com").unwrap();
+        assert!(matches!(command, Command::Tip { amount: 21, memo: Some(ref memo), ref replyee, .. }
+                         if memo == "thanks a lot" && replyee == "@bob:example.com"));
+
+        assert!(matches!(tip(SENDER, "!tip 21", "@bob:example.com").unwrap(), Command::Tip { memo: None, .. }));
+        assert!(matches!(tip(SENDER, "!tip", "@bob:example.com"), Err(BotError::InvalidCommand(_))));
+        assert!(matches!(tip(SENDER, "!tip -5", "@bob:example.com"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn send_takes_amount_recipient_and_memo() {
+        let command = send(SENDER, "!send 100 @bob:example.com for lunch").unwrap();
+        assert!(matches!(command, Command::Send { amount: 100, ref recipient, memo: Some(ref memo), .. }
+                         if recipient == "@bob:example.com" && memo == "for lunch"));
+
+        assert!(matches!(send(SENDER, "!send 100"), Err(BotError::InvalidCommand(_))));
+        assert!(matches!(send(SENDER, "!send lots @bob:example.com"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn invoice_takes_amount_and_memo() {
+        assert!(matches!(invoice(SENDER, "!invoice 1000 coffee").unwrap(),
+                         Command::Invoice { amount: 1000, memo: Some(ref memo), .. } if memo == "coffee"));
+        assert!(matches!(invoice(SENDER, "!invoice"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn pay_takes_an_optional_amount() {
+        assert!(matches!(pay(SENDER, "!pay lnbc1invoice").unwrap(),
+                         Command::Pay { ref invoice, amount: None, .. } if invoice == "lnbc1invoice"));
+        assert!(matches!(pay(SENDER, "!pay lnbc1invoice 50").unwrap(), Command::Pay { amount: Some(50), .. }));
+        assert!(matches!(pay(SENDER, "!pay lnbc1invoice fifty"), Err(BotError::InvalidCommand(_))));
+        assert!(matches!(pay(SENDER, "!pay"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn withdraw_from_strips_the_lightning_scheme() {
+        assert!(matches!(withdraw_from(SENDER, "!withdraw-from lightning:lnurl1abc 10").unwrap(),
+                         Command::WithdrawFrom { ref lnurl, amount: Some(10), .. } if lnurl == "lnurl1abc"));
+        assert!(matches!(pasted_lnurl(SENDER, "lightning:lnurl1abc").unwrap(),
+                         Command::WithdrawFrom { ref lnurl, amount: None, .. } if lnurl == "lnurl1abc"));
+    }
+
+    #[test]
+    fn voucher_needs_a_positive_amount() {
+        assert!(matches!(voucher(SENDER, "!voucher 500").unwrap(), Command::Voucher { amount: 500, .. }));
+        assert!(matches!(voucher(SENDER, "!voucher 0"), Err(BotError::InvalidCommand(_))));
+        assert!(matches!(voucher(SENDER, "!voucher"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn history_limit_is_bounded() {
+        assert!(matches!(history(SENDER, "!history").unwrap(), Command::History { limit: DEFAULT_HISTORY_LIMIT, .. }));
+        assert!(matches!(history(SENDER, "!history 5").unwrap(), Command::History { limit: 5, .. }));
+        assert!(matches!(history(SENDER, "!history 0"), Err(BotError::InvalidCommand(_))));
+        assert!(history(SENDER, format!("!history {}", MAX_HISTORY_LIMIT + 1).as_str()).is_err());
+    }
+
+    #[test]
+    fn notify_takes_on_or_off() {
+        assert!(matches!(notify(SENDER, "!notify on").unwrap(), Command::Notify { enabled: true, .. }));
+        assert!(matches!(notify(SENDER, "!notify off").unwrap(), Command::Notify { enabled: false, .. }));
+        assert!(matches!(notify(SENDER, "!notify maybe"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn fiat_conversions_take_amount_and_currency() {
+        assert!(matches!(fiat_to_sats(SENDER, "!fiat-to-sats 1.5 usd").unwrap(),
+                         Command::FiatToSats { amount, ref currency, .. } if amount == 1.5 && currency == "usd"));
+        assert!(matches!(sats_to_fiat(SENDER, "!sats-to-fiat 1000 eur").unwrap(),
+                         Command::SatsToFiat { amount: 1000, ref currency, .. } if currency == "eur"));
+        assert!(matches!(sats_to_fiat(SENDER, "!sats-to-fiat 1.5 eur"), Err(BotError::InvalidCommand(_))));
+    }
+
+    #[test]
+    fn only_spending_commands_move_money() {
+        assert!(tip(SENDER, "!tip 1", "@bob:example.com").unwrap().moves_money());
+        assert!(donate(SENDER, "!donate 1").unwrap().moves_money());
+        assert!(confirm(SENDER, None).unwrap().moves_money());
+        assert!(!balance(SENDER).unwrap().moves_money());
+        assert!(!invoice(SENDER, "!invoice 1").unwrap().moves_money());
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use matrix_sdk::ruma::UserId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::error::BotError;
+use crate::matrix_bot::business_logic::BusinessLogicContext;
+
+const MIN_SENDABLE_MILLI_SATOSHIS: u64 = 1_000;
+const MAX_SENDABLE_MILLI_SATOSHIS: u64 = 1_000_000_000;
+const COMMENT_ALLOWED: usize = 255;
+
+// Every callback creates an invoice, so strangers get a budget of them per minute.
+// Behind a reverse proxy all requests share the proxy's address.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const MAX_CALLBACKS_PER_ADDRESS: u32 = 30;
+const MAX_CALLBACKS_PER_USER: u32 = 10;
+
+#[derive(Clone)]
+pub struct LightningAddressServer {
+    business_logic_context: BusinessLogicContext,
+    public_url: String,
+    domain: String,
+    matrix_server_name: String,
+    rate_limiter: RateLimiter
+}
+
+/// Counts requests per key in fixed windows, in memory only.
+#[derive(Clone)]
+struct RateLimiter {
+    windows: Arc<Mutex<HashMap<String, (Instant, u32)>>>
+}
+
+impl RateLimiter {
+
+    fn new() -> RateLimiter {
+        RateLimiter {
+            windows: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    // Returns false once the key made more than max_requests within the current window.
+    fn allow(&self, key: &str, max_requests: u32, now: Instant) -> bool {
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|_, (window_start, _)| now.duration_since(*window_start) < RATE_LIMIT_WINDOW);
+
+        let (_, requests) = windows.entry(key.to_string()).or_insert((now, 0));
+        *requests += 1;
+        *requests <= max_requests
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PayRequest {
+    tag: String,
+    callback: String,
+    min_sendable: u64,
+    max_sendable: u64,
+    metadata: String,
+    comment_allowed: usize
+}
+
+#[derive(Debug, Deserialize)]
+struct PayCallbackParams {
+    amount: u64,
+    comment: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+struct PayCallback {
+    pr: String,
+    routes: Vec<String>
+}
+
+#[derive(Debug, Serialize)]
+struct LnUrlError {
+    status: String,
+    reason: String
+}
+
+fn lnurl_error(status_code: StatusCode, reason: &str) -> Response {
+    (status_code, Json(LnUrlError { status: "ERROR".to_string(), reason: reason.to_string() })).into_response()
+}
+
+impl LightningAddressServer {
+
+    pub fn new(business_logic_context: BusinessLogicContext,
+               public_url: &str,
+               domain: &str,
+               matrix_server_name: &str) -> LightningAddressServer {
+        LightningAddressServer {
+            business_logic_context,
+            public_url: public_url.trim_end_matches('/').to_string(),
+            domain: domain.to_string(),
+            matrix_server_name: matrix_server_name.to_string(),
+            rate_limiter: RateLimiter::new()
+        }
+    }
+
+    pub async fn serve(self, listen_address: String) {
+        log::info!("Serving lightning addresses for @<user>:{} as <user>@{} on {} ..",
+                   self.matrix_server_name,
+                   self.domain,
+                   listen_address);
+
+        let app = Router::new()
+            .route("/.well-known/lnurlp/:localpart", get(pay_request))
+            .route("/lnurlp/:localpart/callback", get(pay_callback))
+            .with_state(self);
+
+        let listener = match tokio::net::TcpListener::bind(listen_address.as_str()).await {
+            Ok(listener) => listener,
+            Err(error) => {
+                log::error!("Could not listen on {:?} due to {:?}..", listen_address, error);
+                return
+            }
+        };
+
+        if let Err(error) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+            log::error!("Lightning address server stopped due to {:?}..", error);
+        }
+    }
+
+    // Only users that already have a wallet with us get an address, we don't create wallets for strangers.
+    async fn matrix_id_for_localpart(&self, localpart: &str) -> Option<String> {
+        let user_id = UserId::parse(format!("@{}:{}", localpart.to_lowercase(), self.matrix_server_name)).ok()?;
+        match self.business_logic_context.has_wallet(user_id.as_str()).await {
+            Ok(true) => Some(user_id.to_string()),
+            Ok(false) => None,
+            Err(error) => {
+                log::warn!("Could not look up wallet of {:?} due to {:?}..", user_id, error);
+                None
+            }
+        }
+    }
+
+    // The invoice commits to this exact string through its description hash, so it has to be stable.
+    fn metadata(&self, localpart: &str) -> String {
+        let address = format!("{}@{}", localpart.to_lowercase(), self.domain);
+        serde_json::to_string(&vec![vec!["text/plain".to_string(), format!("Payment to {}", address)],
+                                    vec!["text/identifier".to_string(), address]]).unwrap_or_default()
+    }
+}
+
+async fn pay_request(State(server): State<LightningAddressServer>,
+                     Path(localpart): Path<String>) -> Response {
+    log::info!("processing lightning address request for {:?} ..", localpart);
+
+    if server.matrix_id_for_localpart(localpart.as_str()).await.is_none() {
+        return lnurl_error(StatusCode::NOT_FOUND, "Unknown user")
+    }
+
+    Json(PayRequest {
+        tag: "payRequest".to_string(),
+        callback: format!("{}/lnurlp/{}/callback", server.public_url, localpart.to_lowercase()),
+        min_sendable: MIN_SENDABLE_MILLI_SATOSHIS,
+        max_sendable: MAX_SENDABLE_MILLI_SATOSHIS,
+        metadata: server.metadata(localpart.as_str()),
+        comment_allowed: COMMENT_ALLOWED
+    }).into_response()
+}
+
+async fn pay_callback(State(server): State<LightningAddressServer>,
+                      ConnectInfo(remote_address): ConnectInfo<SocketAddr>,
+                      Path(localpart): Path<String>,
+                      Query(params): Query<PayCallbackParams>) -> Response {
+    log::info!("processing lightning address callback for {:?} ..", localpart);
+
+    // Checked before anything touches the database or LNbits, which only invoices whole Sats
+    if params.amount < MIN_SENDABLE_MILLI_SATOSHIS
+        || params.amount > MAX_SENDABLE_MILLI_SATOSHIS
+        || params.amount % 1000 != 0 {
+        return lnurl_error(StatusCode::BAD_REQUEST, "Amount must be a whole number of Sats within the sendable range")
+    }
+
+    let comment = params.comment.filter(|comment| !comment.trim().is_empty());
+    if comment.as_ref().map(|comment| comment.chars().count() > COMMENT_ALLOWED).unwrap_or(false) {
+        return lnurl_error(StatusCode::BAD_REQUEST, "Comment is too long")
+    }
+
+    let now = Instant::now();
+    if !server.rate_limiter.allow(remote_address.ip().to_string().as_str(), MAX_CALLBACKS_PER_ADDRESS, now)
+        || !server.rate_limiter.allow(localpart.to_lowercase().as_str(), MAX_CALLBACKS_PER_USER, now) {
+        log::warn!("Rate limiting lightning address callback for {:?} from {:?} ..", localpart, remote_address);
+        return lnurl_error(StatusCode::TOO_MANY_REQUESTS, "Too many requests, please try again later")
+    }
+
+    let matrix_id = match server.matrix_id_for_localpart(localpart.as_str()).await {
+        Some(matrix_id) => matrix_id,
+        None => return lnurl_error(StatusCode::NOT_FOUND, "Unknown user")
+    };
+
+    let description_hash = format!("{:x}", Sha256::digest(server.metadata(localpart.as_str()).as_bytes()));
+
+    let bolt11_invoice = server.business_logic_context.receive_via_lightning_address(matrix_id.as_str(),
+                                                                                    params.amount / 1000,
+                                                                                    comment,
+                                                                                    description_hash).await;
+    match bolt11_invoice {
+        Ok(bolt11_invoice) => Json(PayCallback { pr: bolt11_invoice, routes: vec![] }).into_response(),
+        Err(error) => {
+            log::warn!("Could not create lightning address invoice for {:?} due to {:?}..", matrix_id, error);
+            let status_code = match error {
+                BotError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+                BotError::LNBitsUnreachable(_) => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR
+            };
+            lnurl_error(status_code, "Could not create an invoice")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_resets_after_the_window() {
+        let rate_limiter = RateLimiter::new();
+        let now = Instant::now();
+
+        assert!(rate_limiter.allow("alice", 2, now));
+        assert!(rate_limiter.allow("alice", 2, now));
+        assert!(!rate_limiter.allow("alice", 2, now));
+        assert!(rate_limiter.allow("bob", 2, now));
+
+        assert!(rate_limiter.allow("alice", 2, now + RATE_LIMIT_WINDOW));
+    }
+}
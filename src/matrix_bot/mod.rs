@@ -1,29 +1,42 @@
 mod commands;
 mod business_logic;
 mod utils;
+mod lightning_address;
+mod appservice;
+mod verification;
 
 pub mod matrix_bot {
 
     use matrix_sdk::{config::SyncSettings, ruma::events::room::member::StrippedRoomMemberEvent, Client, Room, RoomMemberships, RoomState};
 
     use matrix_sdk::attachment::AttachmentConfig;
+    use matrix_sdk::matrix_auth::{MatrixSession, MatrixSessionTokens};
+    use matrix_sdk::SessionMeta;
+    use matrix_sdk::deserialized_responses::{EncryptionInfo, VerificationLevel, VerificationState};
     use matrix_sdk::room::RoomMember;
     use matrix_sdk::ruma::events::room::message::{AddMentions, ForwardThread, MessageFormat, OriginalRoomMessageEvent, OriginalSyncRoomMessageEvent, Relation, RoomMessageEventContent, TextMessageEventContent, MessageType, RoomMessageEventContentWithoutRelation};
 
     use crate::{Config, DataLayer};
-    use crate::lnbits_client::lnbits_client::LNBitsClient;
+    use crate::error::error::BotError;
+    use crate::lnbits_client::lnbits_client::{LNBitsClient, PaymentStatus};
     use crate::matrix_bot::business_logic::BusinessLogicContext;
+    use crate::matrix_bot::lightning_address::LightningAddressServer;
+    use crate::matrix_bot::appservice::{AppserviceServer, Registration};
+    use crate::matrix_bot::verification;
     use tokio::time::{sleep, Duration};
     use mime;
-    use matrix_sdk::ruma::{MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, ServerName, UserId};
+    use matrix_sdk::ruma::{EventId, IdParseError, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomId, OwnedUserId, RoomId, ServerName, UserId};
+    use matrix_sdk::ruma::events::relation::InReplyTo;
+    use matrix_sdk::ruma::events::reaction::OriginalSyncReactionEvent;
     
     use matrix_sdk::ruma::events::room::message::Relation::Reply;
     use simple_error::{bail, try_with};
     use simple_error::SimpleError;
     use url::Url;
-    use crate::matrix_bot::commands::{balance, Command, donate, help, invoice, party, pay, send, tip, version, fiat_to_sats, sats_to_fiat};
+    use crate::matrix_bot::commands::{balance, Command, CommandOrigin, DirectNotification, donate, help, invoice, party, pay, send, tip, version, fiat_to_sats, sats_to_fiat, history, notify, confirm, decode, withdraw_from, pasted_lnurl, voucher};
     pub use crate::data_layer::data_layer::LNBitsId;
-    use crate::matrix_bot::utils::parse_lnurl;
+    use crate::data_layer::data_layer::Transaction;
+    use crate::matrix_bot::utils::{parse_lnurl, write_secret_file};
 
 
     #[derive(Debug)]
@@ -74,6 +87,28 @@ pub mod matrix_bot {
 
         log::info!("Successfully joined room {}", room.room_id());
 
+        send_welcome(&room, &business_logic_context).await;
+    }
+
+    // Appservice counterpart of auto_join, the invite reaches us through a transaction instead of a sync.
+    async fn join_invited_room(client: Client,
+                               room_id: OwnedRoomId,
+                               business_logic_context: BusinessLogicContext) {
+        log::info!("Autojoining room {}", room_id);
+
+        match client.join_room_by_id(&room_id).await {
+            Ok(room) => {
+                log::info!("Successfully joined room {}", room_id);
+                send_welcome(&room, &business_logic_context).await;
+            },
+            Err(error) => {
+                log::error!("Can't join room {} ({:?})", room_id, error);
+            }
+        }
+    }
+
+    async fn send_welcome(room: &Room,
+                          business_logic_context: &BusinessLogicContext) {
         // Upon succesfull join send a single message
         let content = RoomMessageEventContent::text_plain(
             "Thanks for inviting me. I support the following commands:\n".to_owned() +
@@ -90,6 +125,210 @@ pub mod matrix_bot {
         }
     }
 
+    const INVOICE_WATCH_INTERVAL: Duration = Duration::from_secs(10);
+
+    async fn watch_invoices(client: Client,
+                            business_logic_context: BusinessLogicContext) {
+        loop {
+            sleep(INVOICE_WATCH_INTERVAL).await;
+
+            let paid_invoices = business_logic_context.settle_paid_invoices().await;
+            match paid_invoices {
+                Ok(paid_invoices) => {
+                    for paid_invoice in paid_invoices {
+                        notify_invoice_paid(&client, &business_logic_context, &paid_invoice).await;
+                    }
+                },
+                Err(error) => {
+                    log::warn!("Could not check pending invoices due to {:?}..", error);
+                }
+            }
+
+            let resolved_payments = business_logic_context.settle_outgoing_payments().await;
+            match resolved_payments {
+                Ok(resolved_payments) => {
+                    for (payment, payment_status) in resolved_payments {
+                        notify_payment_resolved(&client, &payment, &payment_status).await;
+                    }
+                },
+                Err(error) => {
+                    log::warn!("Could not check pending payments due to {:?}..", error);
+                }
+            }
+
+            if let Err(error) = business_logic_context.settle_vouchers().await {
+                log::warn!("Could not check open vouchers due to {:?}..", error);
+            }
+        }
+    }
+
+    async fn notify_invoice_paid(client: &Client,
+                                 business_logic_context: &BusinessLogicContext,
+                                 paid_invoice: &Transaction) {
+        // Invoices handed out through the lightning address were not requested in a room, tell the user directly
+        // if they asked for that with !notify
+        if paid_invoice.room_id.is_none() {
+            let recipient = paid_invoice.recipient.clone().unwrap_or_default();
+            if !business_logic_context.wants_dm_notifications(recipient.as_str()).await {
+                return
+            }
+
+            let mut text = format!("You received {} Sats via your lightning address", paid_invoice.amount);
+            if let Some(comment) = &paid_invoice.memo {
+                text.push_str(format!(" with comment {:?}", comment).as_str());
+            }
+            let notification = DirectNotification { recipient, text };
+            if let Err(error) = send_direct_notification(client, business_logic_context, &notification).await {
+                log::warn!("Could not send lightning address payment message due to {:?}..", error);
+            }
+            return
+        }
+
+        let text = match &paid_invoice.memo {
+            Some(memo) => format!("{} your invoice for {} sats ({}) was paid",
+                                  paid_invoice.recipient.clone().unwrap_or_default(),
+                                  paid_invoice.amount,
+                                  memo),
+            None => format!("{} your invoice for {} sats was paid",
+                            paid_invoice.recipient.clone().unwrap_or_default(),
+                            paid_invoice.amount)
+        };
+
+        reply_to_transaction(client, paid_invoice, text).await;
+    }
+
+    // Payments that were still pending when the command was answered
+    async fn notify_payment_resolved(client: &Client,
+                                     payment: &Transaction,
+                                     payment_status: &PaymentStatus) {
+        let text = if payment_status.paid {
+            format!("{} your payment of {} Sats settled with a fee of {} Sats, preimage {}",
+                    payment.sender.clone().unwrap_or_default(),
+                    payment.amount,
+                    payment_status.fee_milli_satoshis() as f64 / 1000.0,
+                    payment_status.preimage.clone().unwrap_or_default())
+        } else {
+            format!("{} your payment of {} Sats failed, the Sats are back in your wallet",
+                    payment.sender.clone().unwrap_or_default(),
+                    payment.amount)
+        };
+
+        reply_to_transaction(client, payment, text).await;
+    }
+
+    // Replies to the command the transaction came from, in the room it was sent in
+    async fn reply_to_transaction(client: &Client,
+                                  transaction: &Transaction,
+                                  text: String) {
+        let room = transaction.room_id.as_deref()
+                                      .and_then(|room_id| RoomId::parse(room_id).ok())
+                                      .and_then(|room_id| client.get_room(&room_id));
+        let room = match room {
+            Some(room) => room,
+            None => {
+                log::warn!("Could not find the room to notify about transaction {:?}..", transaction.id);
+                return
+            }
+        };
+
+        let mut content = RoomMessageEventContent::text_plain(text);
+        if let Some(Ok(event_id)) = transaction.event_id.as_deref().map(EventId::parse) {
+            content.relates_to = Some(Reply { in_reply_to: InReplyTo::new(event_id) });
+        }
+
+        if let Err(error) = room.send(content).await {
+            log::warn!("Could not send message about transaction {:?} due to {:?}..", transaction.id, error);
+        }
+    }
+
+    async fn send_direct_notification(client: &Client,
+                                      business_logic_context: &BusinessLogicContext,
+                                      notification: &DirectNotification) -> Result<(), SimpleError> {
+        let user_id = try_with!(UserId::parse(notification.recipient.as_str()),
+                                "Could not parse recipient");
+
+        let stored_room = business_logic_context.dm_room_for_matrix_id(user_id.as_str())
+                                                .await
+                                                .and_then(|room_id| RoomId::parse(room_id).ok())
+                                                .and_then(|room_id| client.get_room(&room_id))
+                                                .filter(|room| room.state() == RoomState::Joined);
+
+        let room = match stored_room {
+            Some(room) => room,
+            None => {
+                let room = match client.get_dm_room(&user_id) {
+                    Some(room) => room,
+                    None => try_with!(client.create_dm(&user_id).await,
+                                      "Could not create direct message room")
+                };
+                business_logic_context.store_dm_room_for_matrix_id(user_id.as_str(), room.room_id().as_str()).await;
+                room
+            }
+        };
+
+        try_with!(room.send(RoomMessageEventContent::text_plain(notification.text.as_str())).await,
+                  "Could not send direct message");
+
+        Ok(())
+    }
+
+    const CONFIRMATION_REACTION: &str = "✅";
+
+    async fn confirm_by_reaction(room: &Room,
+                                 event: &OriginalSyncReactionEvent,
+                                 encryption_info: Option<&EncryptionInfo>,
+                                 require_verified_devices: bool,
+                                 business_logic_context: &BusinessLogicContext) {
+        let annotation = &event.content.relates_to;
+
+        // Clients may append a variation selector to the emoji
+        if annotation.key.trim_end_matches('\u{fe0f}') != CONFIRMATION_REACTION {
+            return
+        }
+        if !business_logic_context.is_awaiting_confirmation(annotation.event_id.as_str(), event.sender.as_str()) {
+            return
+        }
+
+        log::info!("processing confirmation reaction {:?} ..", event);
+
+        let command = Command::Confirm { sender: event.sender.to_string(),
+                                         prompt_event_id: Some(annotation.event_id.to_string()) };
+        let origin = CommandOrigin::new(room.room_id().as_str(), event.event_id.as_str());
+
+        let command_reply = match check_sending_device(room, &command, encryption_info, require_verified_devices).await {
+            Ok(()) => business_logic_context.processing_command(command, &origin).await,
+            Err(error) => Err(error)
+        };
+        let (mut reply_message, notification) = match command_reply {
+            Ok(command_reply) => {
+                let reply_message = match command_reply.html {
+                    Some(html) => RoomMessageEventContent::text_html(command_reply.text.unwrap_or_default(), html),
+                    None => RoomMessageEventContent::text_plain(command_reply.text.unwrap_or_default())
+                };
+                (reply_message, command_reply.notification)
+            },
+            Err(error) => {
+                log::warn!("Error occurred during business processing {:?}..", error);
+                (RoomMessageEventContent::text_plain(error.user_message()), None)
+            }
+        };
+        reply_message.relates_to = Some(Reply { in_reply_to: InReplyTo::new(annotation.event_id.clone()) });
+
+        if let Err(error) = room.send(reply_message).await {
+            log::warn!("Error occurred while sending response {:?}..", error);
+            return
+        }
+
+        if let Some(notification) = &notification {
+            let notification_result = send_direct_notification(&room.client(),
+                                                               business_logic_context,
+                                                               notification).await;
+            if let Err(error) = notification_result {
+                log::warn!("Error occurred while sending direct notification {:?}..", error);
+            }
+        }
+    }
+
     fn reply_event_id(option: Option<&Relation<RoomMessageEventContentWithoutRelation>>) -> Option<OwnedEventId> {
         if option.is_none() {  None }
         else {
@@ -110,9 +349,8 @@ pub mod matrix_bot {
 
     async fn extract_command(room: &Room,
                              sender: &str,
-                             event: &OriginalSyncRoomMessageEvent,
                              original_event: Option<OwnedEventId>,
-                             extracted_msg_body: &ExtractedMessageBody) -> Result<Command, SimpleError> {
+                             extracted_msg_body: &ExtractedMessageBody) -> Result<Command, BotError> {
         let msg_body = extracted_msg_body.msg_body.clone().unwrap().to_lowercase(); // We don't care about the case of the command.
 
         if last_line(msg_body.as_str()).starts_with("!tip") && !original_event.is_none() {
@@ -125,7 +363,7 @@ pub mod matrix_bot {
                             any_room_event.sender().to_owned()
                         }
                         _ => {
-                            bail!("Could not parse answer {:?}", answer)
+                            return Err(BotError::Internal(format!("Could not parse answer {:?}", answer)))
                         }
                     };
                     tip(sender,
@@ -134,36 +372,35 @@ pub mod matrix_bot {
                 },
                 Err(simple_error) => {
                     log::error!("Error while retrieving original message {:?} ..", simple_error);
-                    bail!("Could not retrieve original message {:?}", simple_error)
+                    Err(BotError::Internal(format!("Could not retrieve original message {:?}", simple_error)))
                 }
             }
         }  else if msg_body.starts_with("!balance") {
             balance(sender)
         } else if msg_body.starts_with("!send") {
-            let msg_body = preprocess_send_message(&extracted_msg_body, room).await;
-            match msg_body {
+            let preprocessed_msg_body = preprocess_send_message(&extracted_msg_body, room).await;
+            match preprocessed_msg_body {
                 Ok(msg_body) => {
                     send(sender, msg_body.as_str())
                 },
-                Err(_) => {
-                    let error_message = "Please use <amount> <username>.\n \
-                                              If usernames are ambiguous write them out in full. I.e. like @username:example-server.com.";
-                    let result = send_reply_to_event_in_room(&room,
-                                                                               &event,
-                                                                          error_message).await;
-                    match result {
-                        Err(error) => {
-                            log::warn!("Could not send reply message due to {:?}..", error);
-                        }
-                        _ => { /* ignore */}
-                    }
-                    Ok(Command::None)
+                Err(error) => {
+                    log::info!("Could not preprocess send message {:?}..", error);
+                    let recipient = msg_body.split_whitespace().nth(2).unwrap_or("").to_string();
+                    Err(BotError::UnknownRecipient(recipient))
                 }
             }
         } else if msg_body.starts_with("!invoice") {
             invoice(sender, msg_body.as_str())
         } else if msg_body.starts_with("!pay") {
             pay(sender, msg_body.as_str())
+        } else if msg_body.starts_with("!withdraw-from") {
+            withdraw_from(sender, msg_body.as_str())
+        } else if msg_body.starts_with("lnurl1") || msg_body.starts_with("lightning:lnurl1") {
+            pasted_lnurl(sender, msg_body.as_str())
+        } else if msg_body.starts_with("!voucher") {
+            voucher(sender, msg_body.as_str())
+        } else if msg_body.starts_with("!decode") {
+            decode(msg_body.as_str())
         } else if msg_body.starts_with("!help") {
             help()
         } else if msg_body.starts_with("!donate") {
@@ -176,6 +413,12 @@ pub mod matrix_bot {
             fiat_to_sats(sender, msg_body.as_str())
         } else if msg_body.starts_with("!sats-to-fiat") {
             sats_to_fiat(sender, msg_body.as_str())
+        } else if msg_body.starts_with("!history") {
+            history(sender, msg_body.as_str())
+        } else if msg_body.starts_with("!notify") {
+            notify(sender, msg_body.as_str())
+        } else if last_line(msg_body.as_str()).starts_with("!confirm") {
+            confirm(sender, original_event.as_ref().map(|event_id| event_id.as_str()))
         } else {
             Ok(Command::None)
         }
@@ -359,6 +602,15 @@ pub mod matrix_bot {
     async fn send_reply_to_event_in_room(room: &Room,
                                          event: &OriginalSyncRoomMessageEvent,
                                          reply: &str) -> Result<(), SimpleError> {
+        send_content_reply_to_event_in_room(room,
+                                            event,
+                                            RoomMessageEventContent::text_plain(reply)).await?;
+        Ok(())
+    }
+
+    async fn send_content_reply_to_event_in_room(room: &Room,
+                                                 event: &OriginalSyncRoomMessageEvent,
+                                                 reply_message: RoomMessageEventContent) -> Result<OwnedEventId, SimpleError> {
         let original_room_message_event = OriginalRoomMessageEvent {
             content: event.content.clone(),
             event_id: event.event_id.clone(),
@@ -368,8 +620,6 @@ pub mod matrix_bot {
             unsigned: event.unsigned.clone(),
         };
 
-        let reply_message = RoomMessageEventContent::text_plain(reply);
-
         let content = reply_message.make_reply_to(
             &original_room_message_event,
             ForwardThread::Yes,
@@ -379,14 +629,231 @@ pub mod matrix_bot {
         log::info!("Replying with content {:?} ..", content);
 
         // Send the message to the room
-        room.send(content).await.map_err(|e| {
+        let response = room.send(content).await.map_err(|e| {
             SimpleError::new(format!("Could not send message: {:?}", e))
         })?;
 
-        Ok(())
+        Ok(response.event_id)
+
+    }
+
+    async fn handle_room_message(event: OriginalSyncRoomMessageEvent,
+                                 room: Room,
+                                 encryption_info: Option<EncryptionInfo>,
+                                 require_verified_devices: bool,
+                                 business_logic_contex: BusinessLogicContext,
+                                 bot_name: String,
+                                 current_time: MilliSecondsSinceUnixEpoch) {
+        if room.state() != RoomState::Joined {
+            return;
+        }
+
+        log::info!("processing event {:?} ..", event);
+
+        let sender = event.sender.as_str();
+        let original_event = reply_event_id(event.content.relates_to.as_ref());
+
+        let extracted_msg_body = extract_body(&event);
+        if extracted_msg_body.msg_body.is_none() { return } // No body to process
+
+        if current_time > event.origin_server_ts {
+            // Event was before I joined, can happen in public rooms.
+            return;
+        }
+
+        let plain_message_body = extracted_msg_body.msg_body.clone().unwrap();
+
+        if plain_message_body.starts_with(bot_name.as_str()) {
+            let result = send_reply_to_event_in_room(&room,
+                                                     &event,
+                                                     "Thanks for you message. I am but a simple bot. I will join any room you invite me to. Please run !help to see what I can do.").await;
+            match result {
+                Err(error) => {
+                    log::warn!("Could not send reply message due to {:?}..", error);
+                }
+                _ => { /* ignore */}
+            }
+            return
+        }
+
+        let command = extract_command(&room,
+                                      sender,
+                                      original_event,
+                                      &extracted_msg_body).await;
+
+
+        match command {
+            Err(error) => {
+                log::warn!("Error occurred while extracting command {:?}..", error);
+                let result = send_reply_to_event_in_room(&room,
+                                                         &event,
+                                                         error.user_message().as_str()).await;
+                match result {
+                    Err(error) => {
+                        log::warn!("Could not even send error message due to {:?}..", error);
+                    }
+                    _ => { /* ignore */}
+                }
+                return
+            }
+            _ => { },
+        };
+        let command = command.unwrap();
+        if command.is_none() { return } // No Command to execute
+
+        let origin = CommandOrigin::new(room.room_id().as_str(), event.event_id.as_str());
+
+        let command_reply = match check_sending_device(&room, &command, encryption_info.as_ref(), require_verified_devices).await {
+            Ok(()) => business_logic_contex.processing_command(command, &origin).await,
+            Err(error) => Err(error)
+        };
+        match command_reply {
+            Err(error) => {
+                log::warn!("Error occurred during business processing {:?}..", error);
+                let result = send_reply_to_event_in_room(&room,
+                                                         &event,
+                                                         error.user_message().as_str()).await;
+                match result {
+                    Err(error) => {
+                        log::warn!("Could not even send error message due to {:?}..", error);
+                    }
+                    _ => { /* ignore */}
+                }
+                return
+            }
+            _ => { },
+        };
+        let command_reply = command_reply.unwrap();
+
+        log::info!("Sending back answer {:?}", command_reply);
+
+        if command_reply.is_empty() {
+            return // No output to give back
+        }
+
+        let reply_message = match command_reply.html {
+            Some(html) => RoomMessageEventContent::text_html(command_reply.text.unwrap(), html),
+            None => RoomMessageEventContent::text_plain(command_reply.text.unwrap())
+        };
+
+        let send_result = send_content_reply_to_event_in_room(&room,
+                                                              &event,
+                                                              reply_message).await;
+        let reply_event_id = match send_result {
+            Err(error) => {
+                log::warn!("Error occurred while sending response {:?}..", error);
+                return
+            }
+            Ok(reply_event_id) => reply_event_id,
+        };
+
+        if let Some(confirmation) = command_reply.confirmation {
+            business_logic_contex.await_confirmation(reply_event_id.as_str(), confirmation);
+            return
+        }
+
+        if let Some(notification) = &command_reply.notification {
+            let notification_result = send_direct_notification(&room.client(),
+                                                               &business_logic_contex,
+                                                               notification).await;
+            if let Err(error) = notification_result {
+                log::warn!("Error occurred while sending direct notification {:?}..", error);
+            }
+        }
 
+        //
+        // TODO(AE) This assumes we don't have image only responses fix once
+        // this changes.
+        //
+
+        // Attaching image to message
+        if command_reply.image.is_some() {
+            // https://stackoverflow.com/questions/42240663/how-to-read-stdioread-from-a-vec-or-slice
+
+
+            let upload_result = room.send_attachment("image",
+                                                     &mime::IMAGE_PNG,
+                                                     command_reply.image.unwrap(),
+                                                     AttachmentConfig::new()).await;
+            match upload_result {
+                Err(error) => {
+                    log::warn!("Error occurred while attaching image {:?}..", error);
+                    return
+                }
+                _ => { },
+            }
+        }
+    }
+
+    async fn handle_reaction(event: OriginalSyncReactionEvent,
+                             room: Room,
+                             encryption_info: Option<EncryptionInfo>,
+                             require_verified_devices: bool,
+                             business_logic_contex: BusinessLogicContext,
+                             current_time: MilliSecondsSinceUnixEpoch) {
+        if room.state() != RoomState::Joined {
+            return;
+        }
+
+        if current_time > event.origin_server_ts {
+            return;
+        }
+
+        confirm_by_reaction(&room,
+                            &event,
+                            encryption_info.as_ref(),
+                            require_verified_devices,
+                            &business_logic_contex).await;
     }
 
+    // A stolen access token gives the attacker a new device, which its owner never signed.
+    async fn check_sending_device(room: &Room,
+                                  command: &Command,
+                                  encryption_info: Option<&EncryptionInfo>,
+                                  require_verified_devices: bool) -> Result<(), BotError> {
+        if !require_verified_devices || !command.moves_money() {
+            return Ok(())
+        }
+
+        let is_encrypted = room.is_encrypted()
+                               .await
+                               .map_err(|error| BotError::Internal(format!("Could not get encryption state of room {:?}", error)))?;
+        if !is_encrypted {
+            return Ok(())
+        }
+
+        let encryption_info = encryption_info.ok_or(BotError::UnverifiedDevice("your message was not encrypted".to_string()))?;
+        check_verification_state(&encryption_info.verification_state)
+    }
+
+    // The device has to be signed by its owner's identity, a token stolen from a device the owner never verified
+    // doesn't do. The bot only verifies its admins, so everyone else's identity is trusted as it is.
+    fn check_verification_state(verification_state: &VerificationState) -> Result<(), BotError> {
+        match verification_state {
+            VerificationState::Verified => Ok(()),
+            VerificationState::Unverified(VerificationLevel::UnverifiedIdentity) => Ok(()),
+            VerificationState::Unverified(VerificationLevel::UnsignedDevice) => {
+                Err(BotError::UnverifiedDevice("you have not verified the device you sent it from".to_string()))
+            },
+            VerificationState::Unverified(VerificationLevel::None(_)) => {
+                Err(BotError::UnverifiedDevice("it was sent from a device I do not know".to_string()))
+            }
+        }
+    }
+
+    fn is_invite_for(event: &serde_json::Value, user_id: &UserId) -> bool {
+        event.get("state_key").and_then(|state_key| state_key.as_str()) == Some(user_id.as_str())
+            && event.pointer("/content/membership").and_then(|membership| membership.as_str()) == Some("invite")
+    }
+
+    const SESSION_FILE: &str = "session.json";
+    const DEVICE_DISPLAY_NAME: &str = "matrix-lightning-tip-bot";
+    const APPSERVICE_DEVICE_ID: &str = "APPSERVICE";
+    const APPSERVICE_EVENT_BUFFER: usize = 256;
+
+    const AUTH_MODE_ACCESS_TOKEN: &str = "access-token";
+    const AUTH_MODE_APPSERVICE: &str = "appservice";
+
     pub struct MatrixBot {
         client: Client,
         business_logic_contex: BusinessLogicContext,
@@ -403,7 +870,13 @@ pub mod matrix_bot {
                 Url::parse(config.matrix_server.as_str())
                     .expect("Couldn't parse the homeserver URL");
 
-            let client = Client::new(homeserver_url).await?;
+            // The store keeps the room state and our encryption keys, so we stay the same device across restarts
+            let client = Client::builder()
+                .homeserver_url(homeserver_url)
+                .sqlite_store(config.matrix_store_path.as_str(), config.matrix_store_passphrase.as_deref())
+                .build()
+                .await
+                .map_err(|error| matrix_sdk::Error::UnknownError(Box::new(error)))?;
 
             let matrix_bot = MatrixBot {
                 business_logic_contex: BusinessLogicContext::new(lnbits_client,
@@ -420,6 +893,11 @@ pub mod matrix_bot {
 
             log::info!("Performing init ..");
 
+            // In appservice mode the events reach us through transactions, syncing only keeps the room state current
+            if self.config.matrix_auth_mode == AUTH_MODE_APPSERVICE {
+                return
+            }
+
             // Dangerous
             let business_logic_context = self.business_logic_contex.clone();
 
@@ -436,162 +914,285 @@ pub mod matrix_bot {
             let business_logic_contex = self.business_logic_contex.clone();
             let bot_name = self.bot_name().clone();
             let current_time = MilliSecondsSinceUnixEpoch::now();
+            let require_verified_devices = self.config.require_verified_devices;
 
             self.client.add_event_handler({
                 let business_logic_contex = business_logic_contex.clone();
                 let bot_name = bot_name.clone();
                 let current_time = current_time.clone();
-                move |event: OriginalSyncRoomMessageEvent, room: Room|{
+                move |event: OriginalSyncRoomMessageEvent, room: Room, encryption_info: Option<EncryptionInfo>|{
                     let business_logic_contex = business_logic_contex.clone();
                     let bot_name = bot_name.clone();
                     async move {
+                        handle_room_message(event,
+                                            room,
+                                            encryption_info,
+                                            require_verified_devices,
+                                            business_logic_contex,
+                                            bot_name,
+                                            current_time).await;
+                    }
+                }
+            });
 
-                        if room.state() != RoomState::Joined {
-                            return;
-                        }
+            self.client.add_event_handler({
+                let business_logic_contex = business_logic_contex.clone();
+                move |event: OriginalSyncReactionEvent, room: Room, encryption_info: Option<EncryptionInfo>| {
+                    let business_logic_contex = business_logic_contex.clone();
+                    async move {
+                        handle_reaction(event,
+                                        room,
+                                        encryption_info,
+                                        require_verified_devices,
+                                        business_logic_contex,
+                                        current_time).await;
+                    }
+                }
+            });
 
-                        log::info!("processing event {:?} ..", event);
+            let admin_users = self.config.matrix_admin_users.iter()
+                                                            .filter_map(|admin_user| match UserId::parse(admin_user.as_str()) {
+                                                                Ok(user_id) => Some(user_id),
+                                                                Err(error) => {
+                                                                    log::warn!("Ignoring admin user {:?} due to {:?}..", admin_user, error);
+                                                                    None
+                                                                }
+                                                            })
+                                                            .collect();
+            verification::add_verification_handlers(&self.client, admin_users);
+        }
 
-                        let sender = event.sender.as_str();
-                        let original_event = reply_event_id(event.content.relates_to.as_ref());
+        fn bot_name(&self) -> String {
+            let parts: Vec<&str> = self.config.matrix_server.split(':').collect();
+            if parts.is_empty() || parts[0].len() < 1 {
+                log::warn!("Could not parse my own name from config, please check it");
+                "".to_string()
+            } else {
+                parts[0][1..].to_owned()
+            }
+        }
 
-                        let extracted_msg_body = extract_body(&event);
-                        if extracted_msg_body.msg_body.is_none() { return } // No body to process
+        fn session_file(&self) -> std::path::PathBuf {
+            std::path::Path::new(self.config.matrix_store_path.as_str()).join(SESSION_FILE)
+        }
 
-                        if current_time > event.origin_server_ts {
-                            // Event was before I joined, can happen in public rooms.
-                            return;
-                        }
+        // Logging in with the password creates a new device, which can't decrypt anything sent to the
+        // previous one, so only do it when there is no session to restore.
+        async fn restore_session_or_login(&self) -> matrix_sdk::Result<()> {
+            let session_file = self.session_file();
 
-                        let plain_message_body = extracted_msg_body.msg_body.clone().unwrap();
-
-                        if plain_message_body.starts_with(bot_name.as_str()) {
-                            let result = send_reply_to_event_in_room(&room,
-                                                                     &event,
-                                                                     "Thanks for you message. I am but a simple bot. I will join any room you invite me to. Please run !help to see what I can do.").await;
-                            match result {
-                                Err(error) => {
-                                    log::warn!("Could not send reply message due to {:?}..", error);
-                                }
-                                _ => { /* ignore */}
-                            }
-                            return
-                        }
+            if tokio::fs::try_exists(&session_file).await? {
+                log::info!("Restoring session from {:?} ..", session_file);
 
-                        let command = extract_command(&room,
-                                                      sender,
-                                                      &event,
-                                                      original_event,
-                                                      &extracted_msg_body).await;
-
-
-                        match command {
-                            Err(error) => {
-                                log::warn!("Error occurred while extracting command {:?}..", error);
-                                let result = send_reply_to_event_in_room(&room,
-                                                                         &event,
-                                                                         "I did not understand that command. Please use '!help' to list the commands. Please write usernames in plain text").await;
-                                match result {
-                                    Err(error) => {
-                                        log::warn!("Could not even send error message due to {:?}..", error);
-                                    }
-                                    _ => { /* ignore */}
-                                }
-                                return
-                            }
-                            _ => { },
-                        };
-                        let command = command.unwrap();
-                        if command.is_none() { return } // No Command to execute
-
-                        let command_reply = business_logic_contex.processing_command(command).await;
-                        match command_reply {
-                            Err(error) => {
-                                log::warn!("Error occurred during business processing {:?}..", error);
-                                let result = send_reply_to_event_in_room(&room,
-                                                                         &event,
-                                                                         "I seem to be experiencing a problem please try again later").await;
-                                match result {
-                                    Err(error) => {
-                                        log::warn!("Could not even send error message due to {:?}..", error);
-                                    }
-                                    _ => { /* ignore */}
-                                }
-                                return
-                            }
-                            _ => { },
-                        };
-                        let command_reply = command_reply.unwrap();
+                let session: MatrixSession = serde_json::from_str(tokio::fs::read_to_string(&session_file).await?.as_str())?;
+                self.client.restore_session(session).await?;
 
-                        log::info!("Sending back answer {:?}", command_reply);
+                log::info!("Restored session of device {:?} ..", self.client.device_id());
+                return Ok(())
+            }
 
-                        if command_reply.is_empty() {
-                            return // No output to give back
-                        }
+            log::info!("Loging client in ..");
 
-                        let send_result = send_reply_to_event_in_room(&room,
-                                                                      &event,
-                                                                      command_reply.text.unwrap().as_str()).await;
-                        match send_result {
-                            Err(error) => {
-                                log::warn!("Error occurred while sending response {:?}..", error);
-                                return
-                            }
-                            _ => { },
-                        };
-
-                        //
-                        // TODO(AE) This assumes we don't have image only responses fix once
-                        // this changes.
-                        //
-
-                        // Attaching image to message
-                        if command_reply.image.is_some() {
-                            // https://stackoverflow.com/questions/42240663/how-to-read-stdioread-from-a-vec-or-slice
-
-
-                            let upload_result = room.send_attachment("image",
-                                                                     &mime::IMAGE_PNG,
-                                                                     command_reply.image.unwrap(),
-                                                                     AttachmentConfig::new()).await;
-                            match upload_result {
-                                Err(error) => {
-                                    log::warn!("Error occurred while attaching image {:?}..", error);
-                                    return
-                                }
-                                _ => { },
+            self.client
+                .matrix_auth()
+                .login_username(self.config.matrix_username.as_str(),
+                                self.config.matrix_password.as_deref().unwrap_or_default())
+                .initial_device_display_name(DEVICE_DISPLAY_NAME)
+                .await?;
+
+            if let Some(session) = self.client.matrix_auth().session() {
+                tokio::fs::create_dir_all(self.config.matrix_store_path.as_str()).await?;
+                // The access token in it is as good as the password
+                write_secret_file(&session_file, serde_json::to_string(&session)?.as_str()).await?;
+                log::info!("Stored session of device {:?} in {:?} ..", session.meta.device_id, session_file);
+            }
+
+            Ok(())
+        }
+
+        fn bot_user_id(&self) -> Result<OwnedUserId, IdParseError> {
+            UserId::parse(self.config.matrix_username.as_str())
+        }
+
+        // The token was issued elsewhere, e.g. through SSO, so there is no password to log in with.
+        async fn restore_access_token_session(&self) -> matrix_sdk::Result<()> {
+            log::info!("Restoring session from access token ..");
+
+            let session = MatrixSession {
+                meta: SessionMeta { user_id: self.bot_user_id()?,
+                                    device_id: self.config.matrix_device_id.clone().unwrap_or_default().as_str().into() },
+                tokens: MatrixSessionTokens { access_token: self.config.matrix_access_token.clone().unwrap_or_default(),
+                                              refresh_token: None }
+            };
+            self.client.restore_session(session).await?;
+
+            log::info!("Restored session of device {:?} ..", self.client.device_id());
+            Ok(())
+        }
+
+        pub async fn generate_appservice_registration(config: &Config) -> Result<(), SimpleError> {
+            let bot_user_id = try_with!(UserId::parse(config.matrix_username.as_str()),
+                                        "Bot username has to be a full user id");
+            let path = config.appservice_registration.clone().unwrap_or_default();
+
+            let registration = Registration::generate(&bot_user_id,
+                                                      config.appservice_url.clone().unwrap_or_default().as_str());
+            registration.save(path.as_str()).await?;
+
+            log::info!("Wrote appservice registration to {:?}, add it to the app_service_config_files of your homeserver.", path);
+            Ok(())
+        }
+
+        // The homeserver pushes our events, we still sync as the client only knows rooms it has seen in a sync.
+        async fn run_appservice(&self) -> matrix_sdk::Result<()> {
+            let registration = Registration::load(self.config.appservice_registration.clone().unwrap_or_default().as_str())
+                .await
+                .map_err(|error| matrix_sdk::Error::UnknownError(Box::new(error)))?;
+
+            let bot_user_id = self.bot_user_id()?;
+
+            log::info!("Running as appservice user {} ..", bot_user_id);
+
+            let session = MatrixSession {
+                meta: SessionMeta { user_id: bot_user_id.clone(),
+                                    device_id: APPSERVICE_DEVICE_ID.into() },
+                tokens: MatrixSessionTokens { access_token: registration.as_token.clone(),
+                                              refresh_token: None }
+            };
+            self.client.restore_session(session).await?;
+
+            let response = self.client.sync_once(SyncSettings::default()).await?;
+            let mut next_batch = response.next_batch;
+
+            self.spawn_background_tasks();
+
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(APPSERVICE_EVENT_BUFFER);
+            tokio::spawn(AppserviceServer::new(&registration, sender)
+                             .serve(self.config.appservice_listen_address.clone().unwrap_or_default()));
+
+            let bot_name = self.bot_name();
+            let current_time = MilliSecondsSinceUnixEpoch::now();
+
+            while let Some(event) = receiver.recv().await {
+                let room_id = event.get("room_id")
+                                   .and_then(|room_id| room_id.as_str())
+                                   .and_then(|room_id| RoomId::parse(room_id).ok());
+                let room_id = match room_id {
+                    Some(room_id) => room_id,
+                    None => continue
+                };
+                let event_type = event.get("type").and_then(|event_type| event_type.as_str()).unwrap_or_default().to_string();
+
+                if event_type == "m.room.member" {
+                    if is_invite_for(&event, &bot_user_id) {
+                        tokio::spawn(join_invited_room(self.client.clone(),
+                                                       room_id,
+                                                       self.business_logic_contex.clone()));
+                    }
+                    continue
+                }
+
+                let room = match self.client.get_room(&room_id) {
+                    Some(room) => room,
+                    None => {
+                        // Catch up on rooms we joined since the last sync
+                        let settings = SyncSettings::default().token(next_batch.clone()).timeout(Duration::ZERO);
+                        match self.client.sync_once(settings).await {
+                            Ok(response) => next_batch = response.next_batch,
+                            Err(error) => log::warn!("Could not sync room state due to {:?}..", error)
+                        }
+                        match self.client.get_room(&room_id) {
+                            Some(room) => room,
+                            None => {
+                                log::warn!("Ignoring event from unknown room {}", room_id);
+                                continue
                             }
                         }
-
                     }
+                };
+
+                match event_type.as_str() {
+                    "m.room.message" => match serde_json::from_value::<OriginalSyncRoomMessageEvent>(event) {
+                        Ok(event) => {
+                            tokio::spawn(handle_room_message(event,
+                                                             room,
+                                                             None,
+                                                             self.config.require_verified_devices,
+                                                             self.business_logic_contex.clone(),
+                                                             bot_name.clone(),
+                                                             current_time));
+                        },
+                        Err(error) => log::warn!("Could not parse message event due to {:?}..", error)
+                    },
+                    "m.reaction" => match serde_json::from_value::<OriginalSyncReactionEvent>(event) {
+                        Ok(event) => {
+                            tokio::spawn(handle_reaction(event,
+                                                         room,
+                                                         None,
+                                                         self.config.require_verified_devices,
+                                                         self.business_logic_contex.clone(),
+                                                         current_time));
+                        },
+                        Err(error) => log::warn!("Could not parse reaction event due to {:?}..", error)
+                    },
+                    _ => { }
                 }
-            });
+            }
+
+            Ok(())
         }
 
-        fn bot_name(&self) -> String {
-            let parts: Vec<&str> = self.config.matrix_server.split(':').collect();
-            if parts.is_empty() || parts[0].len() < 1 {
-                log::warn!("Could not parse my own name from config, please check it");
-                "".to_string()
-            } else {
-                parts[0][1..].to_owned()
+        fn spawn_background_tasks(&self) {
+            // Only start watching once the rooms are known, otherwise we can't reply in them
+            tokio::spawn(watch_invoices(self.client.clone(),
+                                        self.business_logic_contex.clone()));
+
+            if let Some(lightning_address_server) = self.lightning_address_server() {
+                tokio::spawn(lightning_address_server.serve(self.config.lnurl_listen_address.clone().unwrap()));
             }
         }
 
+        fn lightning_address_server(&self) -> Option<LightningAddressServer> {
+            self.config.lnurl_listen_address.as_ref()?;
+
+            let public_url = self.config.lnurl_public_url.clone()?;
+            let domain = match Url::parse(public_url.as_str()).ok().and_then(|url| url.host_str().map(|host| host.to_string())) {
+                Some(domain) => domain,
+                None => {
+                    log::error!("Could not parse a domain from {:?}, not serving lightning addresses", public_url);
+                    return None
+                }
+            };
+
+            Some(LightningAddressServer::new(self.business_logic_contex.clone(),
+                                             public_url.as_str(),
+                                             domain.as_str(),
+                                             self.config.lnurl_matrix_server_name.clone()?.as_str()))
+        }
+
         pub async fn sync(&self) -> matrix_sdk::Result<()>  {
             log::info!("Starting sync ..");
 
-            let user_id = self.config.matrix_username.as_str();
+            match self.config.matrix_auth_mode.as_str() {
+                AUTH_MODE_ACCESS_TOKEN => self.restore_access_token_session().await?,
+                AUTH_MODE_APPSERVICE => return self.run_appservice().await,
+                _ => self.restore_session_or_login().await?
+            }
 
-            log::info!("Loging client in ..");
+            log::info!("Done with preliminary steps ..");
 
-            self.client
-                .matrix_auth()
-                .login_username(user_id, self.config.matrix_password.as_str()).await?;
+            let response = self.client.sync_once(SyncSettings::default()).await?;
 
-            log::info!("Done with preliminary steps ..");
+            // Needs the device keys from the first sync
+            verification::bootstrap_cross_signing(&self.client,
+                                                  self.config.matrix_username.as_str(),
+                                                  self.config.matrix_password.as_deref()).await;
+            verification::enable_key_backup(&self.client,
+                                            self.config.matrix_recovery_key.as_deref(),
+                                            self.config.store_recovery_key,
+                                            self.config.matrix_store_path.as_str()).await;
 
-            let response = self.client.sync_once(SyncSettings::default()).await.unwrap();
+            self.spawn_background_tasks();
 
             let settings = SyncSettings::default().token(response.next_batch);
 
@@ -600,4 +1201,24 @@ pub mod matrix_bot {
             Ok(())
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use matrix_sdk::deserialized_responses::DeviceLinkProblem;
+
+        #[test]
+        fn only_devices_signed_by_their_owner_pass() {
+            assert!(check_verification_state(&VerificationState::Verified).is_ok());
+            assert!(check_verification_state(&VerificationState::Unverified(VerificationLevel::UnverifiedIdentity)).is_ok());
+
+            let unverified_levels = vec![VerificationLevel::UnsignedDevice,
+                                         VerificationLevel::None(DeviceLinkProblem::MissingDevice),
+                                         VerificationLevel::None(DeviceLinkProblem::InsecureSource)];
+            for unverified_level in unverified_levels {
+                let result = check_verification_state(&VerificationState::Unverified(unverified_level.clone()));
+                assert!(matches!(result, Err(BotError::UnverifiedDevice(_))), "{:?} was accepted", unverified_level);
+            }
+        }
+    }
 }
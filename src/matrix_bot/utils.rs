@@ -1,6 +1,11 @@
 use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use lightning_invoice::Currency;
 use lnurl::lightning_address::LightningAddress;
 use lnurl::lnurl::LnUrl;
+use tokio::io::AsyncWriteExt;
+use crate::error::error::BotError;
 
 pub fn parse_lnurl(input: &str) -> Option<LnUrl> {
     match LnUrl::from_str(input) {
@@ -11,3 +16,220 @@ pub fn parse_lnurl(input: &str) -> Option<LnUrl> {
         },
     }
 }
+
+pub fn payment_hash_from_bolt11(bolt11: &str) -> Option<String> {
+    match str::parse::<lightning_invoice::Bolt11Invoice>(bolt11) {
+        Ok(invoice) => Some(invoice.payment_hash().to_string()),
+        Err(_) => None
+    }
+}
+
+pub fn amount_in_sats_from_bolt11(bolt11: &str) -> Option<u64> {
+    match str::parse::<lightning_invoice::Bolt11Invoice>(bolt11) {
+        Ok(invoice) => invoice.amount_milli_satoshis().map(|amount| amount / 1000),
+        Err(_) => None
+    }
+}
+
+pub fn escape_html(input: &str) -> String {
+    input.replace('&', "&amp;")
+         .replace('<', "&lt;")
+         .replace('>', "&gt;")
+         .replace('"', "&quot;")
+         .replace('\'', "&#39;")
+}
+
+pub fn format_payment_time(time: &Option<serde_json::Value>) -> String {
+    match time {
+        Some(serde_json::Value::Number(number)) => {
+            match number.as_i64().and_then(|seconds| DateTime::from_timestamp(seconds, 0)) {
+                Some(date_time) => date_time.format("%Y-%m-%d %H:%M").to_string(),
+                None => number.to_string()
+            }
+        },
+        Some(serde_json::Value::String(string)) => {
+            match NaiveDateTime::parse_from_str(string.as_str(), "%Y-%m-%dT%H:%M:%S%.f") {
+                Ok(date_time) => date_time.format("%Y-%m-%d %H:%M").to_string(),
+                Err(_) => match DateTime::parse_from_rfc3339(string.as_str()) {
+                    Ok(date_time) => date_time.format("%Y-%m-%d %H:%M").to_string(),
+                    Err(_) => string.clone()
+                }
+            }
+        },
+        _ => "unknown".to_string()
+    }
+}
+
+// An invoice whose expiry can't be represented is treated as expired.
+pub fn invoice_is_expired_at(invoice: &lightning_invoice::Bolt11Invoice, now: Duration) -> bool {
+    match invoice.duration_since_epoch().checked_add(invoice.expiry_time()) {
+        Some(expires_at) => expires_at <= now,
+        None => true
+    }
+}
+
+pub fn invoice_is_expired(invoice: &lightning_invoice::Bolt11Invoice) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    invoice_is_expired_at(invoice, now)
+}
+
+pub fn bolt11_is_expired(bolt11: &str) -> bool {
+    match str::parse::<lightning_invoice::Bolt11Invoice>(bolt11) {
+        Ok(invoice) => invoice_is_expired(&invoice),
+        Err(_) => true
+    }
+}
+
+pub fn description_from_bolt11(bolt11: &str) -> Option<String> {
+    match str::parse::<lightning_invoice::Bolt11Invoice>(bolt11) {
+        Ok(invoice) => match invoice.description() {
+            lightning_invoice::Bolt11InvoiceDescription::Direct(description) => Some(description.to_string()),
+            lightning_invoice::Bolt11InvoiceDescription::Hash(_) => None
+        },
+        Err(_) => None
+    }
+}
+
+pub fn currency_for_network(network: &str) -> Option<Currency> {
+    match network {
+        "bitcoin" => Some(Currency::Bitcoin),
+        "testnet" => Some(Currency::BitcoinTestnet),
+        "signet" => Some(Currency::Signet),
+        "regtest" => Some(Currency::Regtest),
+        _ => None
+    }
+}
+
+pub fn network_for_currency(currency: &Currency) -> &'static str {
+    match currency {
+        Currency::Bitcoin => "bitcoin",
+        Currency::BitcoinTestnet => "testnet",
+        Currency::Signet => "signet",
+        Currency::Regtest => "regtest",
+        Currency::Simnet => "simnet"
+    }
+}
+
+#[derive(Debug)]
+pub struct DecodedInvoice {
+    pub amount_in_sats: Option<u64>,
+    pub description: Option<String>,
+    pub payee: String,
+    pub payment_hash: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub is_expired: bool,
+    pub currency: Currency
+}
+
+impl DecodedInvoice {
+    pub fn summary(&self) -> String {
+        format!("Amount: {}\nDescription: {}\nPayee node: {}\nPayment hash: {}\nExpires: {}\nNetwork: {}",
+                self.amount_in_sats.map(|amount| format!("{} Sats", amount)).unwrap_or_else(|| "not specified".to_string()),
+                self.description.clone().unwrap_or_else(|| "none".to_string()),
+                self.payee,
+                self.payment_hash,
+                match self.expires_at {
+                    Some(expires_at) if self.is_expired => format!("{} (expired)", expires_at.format("%Y-%m-%d %H:%M UTC")),
+                    Some(expires_at) => expires_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                    None => "unknown".to_string()
+                },
+                network_for_currency(&self.currency))
+    }
+}
+
+pub fn decode_bolt11(bolt11: &str) -> Result<DecodedInvoice, BotError> {
+    let invoice = str::parse::<lightning_invoice::Bolt11Invoice>(bolt11)
+                      .map_err(|e| BotError::InvalidInvoice(e.to_string()))?;
+
+    let expires_at = invoice.expires_at()
+                            .and_then(|expires_at| DateTime::from_timestamp(expires_at.as_secs() as i64, 0));
+
+    Ok(DecodedInvoice {
+        amount_in_sats: invoice.amount_milli_satoshis().map(|amount| amount / 1000),
+        description: description_from_bolt11(bolt11),
+        payee: invoice.recover_payee_pub_key().to_string(),
+        payment_hash: invoice.payment_hash().to_string(),
+        expires_at,
+        is_expired: invoice_is_expired(&invoice),
+        currency: invoice.currency()
+    })
+}
+
+// Readable by the bot's user only, and never replaces a secret written before.
+pub async fn write_secret_file(path: &std::path::Path, content: &str) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    file.write_all(content.as_bytes()).await?;
+    file.sync_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // BOLT 11 test vectors, created at 1496314658 and valid for 60 seconds
+    const COFFEE_INVOICE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+    const COFFEE_INVOICE_CREATED_AT: u64 = 1496314658;
+    const TESTNET_INVOICE: &str = "lntb20m1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygshp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfpp3x9et2e20v6pu37c5d9vax37wxq72un989qrsgqdj545axuxtnfemtpwkc45hx9d2ft7x04mt8q7y6t0k2dge9e7h8kpy9p34ytyslj3yu569aalz2xdk8xkd7ltxqld94u8h2esmsmacgpghe9k8";
+
+    #[test]
+    fn invoice_expires_after_its_expiry_time() {
+        let invoice = str::parse::<lightning_invoice::Bolt11Invoice>(COFFEE_INVOICE).unwrap();
+
+        assert!(!invoice_is_expired_at(&invoice, Duration::from_secs(COFFEE_INVOICE_CREATED_AT)));
+        assert!(!invoice_is_expired_at(&invoice, Duration::from_secs(COFFEE_INVOICE_CREATED_AT + 59)));
+        assert!(invoice_is_expired_at(&invoice, Duration::from_secs(COFFEE_INVOICE_CREATED_AT + 60)));
+        assert!(invoice_is_expired(&invoice));
+    }
+
+    #[test]
+    fn unparsable_invoices_count_as_expired() {
+        assert!(bolt11_is_expired(COFFEE_INVOICE));
+        assert!(bolt11_is_expired("lnbc1notaninvoice"));
+    }
+
+    #[test]
+    fn bolt11_fields_are_extracted() {
+        assert_eq!(amount_in_sats_from_bolt11(COFFEE_INVOICE), Some(250_000));
+        assert_eq!(payment_hash_from_bolt11(COFFEE_INVOICE).as_deref(),
+                   Some("0001020304050607080900010203040506070809000102030405060708090102"));
+        assert_eq!(description_from_bolt11(COFFEE_INVOICE).as_deref(), Some("1 cup coffee"));
+        assert_eq!(amount_in_sats_from_bolt11("garbage"), None);
+        assert_eq!(payment_hash_from_bolt11("garbage"), None);
+    }
+
+    #[test]
+    fn decoded_invoice_knows_its_network() {
+        let decoded_invoice = decode_bolt11(COFFEE_INVOICE).unwrap();
+        assert_eq!(decoded_invoice.amount_in_sats, Some(250_000));
+        assert!(decoded_invoice.is_expired);
+        assert_eq!(network_for_currency(&decoded_invoice.currency), "bitcoin");
+
+        let decoded_invoice = decode_bolt11(TESTNET_INVOICE).unwrap();
+        assert_eq!(decoded_invoice.amount_in_sats, Some(2_000_000));
+        assert_eq!(network_for_currency(&decoded_invoice.currency), "testnet");
+        assert_eq!(currency_for_network("testnet"), Some(decoded_invoice.currency));
+
+        assert!(matches!(decode_bolt11("lnbc1notaninvoice"), Err(BotError::InvalidInvoice(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn secret_files_are_private_and_never_replaced() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("mltb-test-{}.json", uuid::Uuid::new_v4()));
+        write_secret_file(&path, "secret").await.unwrap();
+
+        let metadata = tokio::fs::metadata(&path).await.unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+        assert!(write_secret_file(&path, "other secret").await.is_err());
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "secret");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
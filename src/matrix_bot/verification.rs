@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use futures_util::StreamExt;
+use matrix_sdk::attachment::AttachmentConfig;
+use matrix_sdk::encryption::verification::{QrVerification, QrVerificationState, SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState};
+use matrix_sdk::ruma::api::client::uiaa;
+use matrix_sdk::ruma::events::key::verification::request::ToDeviceKeyVerificationRequestEvent;
+use matrix_sdk::ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent};
+use matrix_sdk::ruma::{OwnedUserId, UserId};
+use matrix_sdk::{Client, Room};
+use qrcode_generator::QrCodeEcc;
+use crate::matrix_bot::utils::write_secret_file;
+
+const RECOVERY_KEY_FILE: &str = "recovery-key.txt";
+
+// Verifications waiting for their admin to compare the emojis or the scan, by admin
+type PendingVerifications = Arc<Mutex<HashMap<OwnedUserId, Verification>>>;
+
+// Cross-signing lets users verify the bot once instead of every device it ever logged in with.
+pub async fn bootstrap_cross_signing(client: &Client,
+                                     user_id: &str,
+                                     password: Option<&str>) {
+    let error = match client.encryption().bootstrap_cross_signing_if_needed(None).await {
+        Ok(()) => {
+            log::info!("Cross-signing is set up ..");
+            return
+        },
+        Err(error) => error
+    };
+
+    // The homeserver wants us to authenticate again, we can only do that with a password
+    let (response, password) = match (error.as_uiaa_response(), password) {
+        (Some(response), Some(password)) => (response, password),
+        _ => {
+            log::warn!("Could not bootstrap cross-signing due to {:?}..", error);
+            return
+        }
+    };
+
+    let mut auth = uiaa::Password::new(uiaa::UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+                                       password.to_string());
+    auth.session = response.session.clone();
+
+    match client.encryption().bootstrap_cross_signing_if_needed(Some(uiaa::AuthData::Password(auth))).await {
+        Ok(()) => log::info!("Cross-signing is set up .."),
+        Err(error) => log::warn!("Could not bootstrap cross-signing due to {:?}..", error)
+    }
+}
+
+// Without a backup the bot loses the keys of encrypted rooms together with its matrix store.
+pub async fn enable_key_backup(client: &Client,
+                               recovery_key: Option<&str>,
+                               store_recovery_key: bool,
+                               store_path: &str) {
+    let recovery = client.encryption().recovery();
+
+    if let Some(recovery_key) = recovery_key {
+        match recovery.recover(recovery_key).await {
+            Ok(()) => log::info!("Restored secrets from server side key backup .."),
+            Err(error) => log::warn!("Could not restore secrets with the configured recovery key due to {:?}..", error)
+        }
+        return
+    }
+
+    match client.encryption().backups().exists_on_server().await {
+        Ok(true) => {
+            log::warn!("A key backup exists on the server, set --matrix-recovery-key to use it");
+            return
+        },
+        Ok(false) => { },
+        Err(error) => {
+            log::warn!("Could not check for a key backup due to {:?}..", error);
+            return
+        }
+    }
+
+    if !store_recovery_key {
+        log::warn!("No key backup exists, set --store-recovery-key to create one");
+        return
+    }
+
+    let recovery_key = match recovery.enable().await {
+        Ok(recovery_key) => recovery_key,
+        Err(error) => {
+            log::warn!("Could not enable key backup due to {:?}..", error);
+            return
+        }
+    };
+
+    // Logs tend to end up in places the key should not, so it only goes into the store directory
+    let recovery_key_file = std::path::Path::new(store_path).join(RECOVERY_KEY_FILE);
+    match write_secret_file(&recovery_key_file, recovery_key.as_str()).await {
+        Ok(()) => log::warn!("Enabled key backup, move the recovery key from {:?} into --matrix-recovery-key", recovery_key_file),
+        Err(error) => log::error!("Enabled key backup but could not store the recovery key due to {:?}..", error)
+    }
+}
+
+pub fn add_verification_handlers(client: &Client, admin_users: Vec<OwnedUserId>) {
+    let pending_verifications: PendingVerifications = Arc::new(Mutex::new(HashMap::new()));
+
+    client.add_event_handler({
+        let admin_users = admin_users.clone();
+        let pending_verifications = pending_verifications.clone();
+        move |event: ToDeviceKeyVerificationRequestEvent, client: Client| {
+            let admin_users = admin_users.clone();
+            let pending_verifications = pending_verifications.clone();
+            async move {
+                let request = client.encryption()
+                                    .get_verification_request(&event.sender, &event.content.transaction_id)
+                                    .await;
+                if let Some(request) = request {
+                    tokio::spawn(handle_verification_request(request, None, admin_users, pending_verifications));
+                }
+            }
+        }
+    });
+
+    client.add_event_handler({
+        let admin_users = admin_users.clone();
+        let pending_verifications = pending_verifications.clone();
+        move |event: OriginalSyncRoomMessageEvent, client: Client, room: Room| {
+            let admin_users = admin_users.clone();
+            let pending_verifications = pending_verifications.clone();
+            async move {
+                match &event.content.msgtype {
+                    MessageType::VerificationRequest(_) => {
+                        let request = client.encryption()
+                                            .get_verification_request(&event.sender, &event.event_id)
+                                            .await;
+                        if let Some(request) = request {
+                            tokio::spawn(handle_verification_request(request, Some(room), admin_users, pending_verifications));
+                        }
+                    },
+                    MessageType::Text(text) if is_admin(&admin_users, &event.sender) => {
+                        let body = text.body.trim().to_lowercase();
+                        if body.starts_with("!verify") {
+                            handle_verify_command(body.as_str(), &event.sender, &room, &pending_verifications).await;
+                        }
+                    },
+                    _ => { }
+                }
+            }
+        }
+    });
+}
+
+fn is_admin(admin_users: &[OwnedUserId], user_id: &UserId) -> bool {
+    admin_users.iter().any(|admin_user| admin_user == user_id)
+}
+
+// Only the admin can tell whether what we see matches their side, so we never confirm on our own.
+async fn handle_verify_command(body: &str,
+                               sender: &UserId,
+                               room: &Room,
+                               pending_verifications: &PendingVerifications) {
+    let confirm = match body.split_whitespace().nth(1) {
+        Some("confirm") => true,
+        Some("cancel") => false,
+        _ => {
+            send_text(room, "Please use !verify confirm or !verify cancel").await;
+            return
+        }
+    };
+
+    let verification = pending_verifications.lock().unwrap().remove(sender);
+    let verification = match verification {
+        Some(verification) => verification,
+        None => {
+            send_text(room, "There is no verification waiting for your confirmation").await;
+            return
+        }
+    };
+
+    let result = match (verification, confirm) {
+        (Verification::SasV1(sas), true) => sas.confirm().await,
+        (Verification::SasV1(sas), false) => sas.cancel().await,
+        (Verification::QrV1(qr), true) => qr.confirm().await,
+        (Verification::QrV1(qr), false) => qr.cancel().await,
+        _ => Ok(())
+    };
+    if let Err(error) = result {
+        log::warn!("Could not answer verification of {} due to {:?}..", sender, error);
+    }
+}
+
+async fn send_text(room: &Room, text: &str) {
+    if let Err(error) = room.send(RoomMessageEventContent::text_plain(text)).await {
+        log::warn!("Could not send verification message due to {:?}..", error);
+    }
+}
+
+async fn handle_verification_request(request: VerificationRequest,
+                                     room: Option<Room>,
+                                     admin_users: Vec<OwnedUserId>,
+                                     pending_verifications: PendingVerifications) {
+    let sender = request.other_user_id().to_owned();
+
+    if !is_admin(&admin_users, &sender) {
+        log::info!("Refusing verification request of {} who is not an admin ..", sender);
+        if let Err(error) = request.cancel().await {
+            log::warn!("Could not cancel verification request due to {:?}..", error);
+        }
+        return
+    }
+
+    log::info!("processing verification request of {} ..", sender);
+
+    let mut changes = request.changes();
+
+    if let Err(error) = request.accept().await {
+        log::warn!("Could not accept verification request due to {:?}..", error);
+        return
+    }
+
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Ready { .. } => {
+                if let Some(room) = &room {
+                    show_qr_code(&request, room).await;
+                }
+            },
+            VerificationRequestState::Transitioned { verification } => {
+                match verification {
+                    Verification::SasV1(sas) => { tokio::spawn(handle_sas_verification(sas, room.clone(), pending_verifications.clone())); },
+                    Verification::QrV1(qr) => { tokio::spawn(handle_qr_verification(qr, room.clone(), pending_verifications.clone())); },
+                    _ => { }
+                }
+                break
+            },
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+            _ => { }
+        }
+    }
+}
+
+// The admin compares the emojis on their side with the ones we post, and tells us with !verify
+// whether they match. Outside of a room the emojis only end up in the log.
+async fn handle_sas_verification(sas: SasVerification, room: Option<Room>, pending_verifications: PendingVerifications) {
+    let mut changes = sas.changes();
+
+    if !sas.we_started() {
+        if let Err(error) = sas.accept().await {
+            log::warn!("Could not accept emoji verification due to {:?}..", error);
+            return
+        }
+    }
+
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::KeysExchanged { emojis, decimals } => {
+                let short_auth_string = match emojis {
+                    Some(emojis) => emojis.emojis.iter()
+                                                 .map(|emoji| format!("{} ({})", emoji.symbol, emoji.description))
+                                                 .collect::<Vec<String>>()
+                                                 .join(" "),
+                    None => format!("{} {} {}", decimals.0, decimals.1, decimals.2)
+                };
+                log::info!("Verification with {} shows {}, waiting for !verify confirm ..", sas.other_user_id(), short_auth_string);
+
+                pending_verifications.lock().unwrap().insert(sas.other_user_id().to_owned(), Verification::SasV1(sas.clone()));
+
+                if let Some(room) = &room {
+                    let text = format!("I see {}\nIf your device shows the same reply !verify confirm, otherwise !verify cancel",
+                                       short_auth_string);
+                    send_text(room, text.as_str()).await;
+                }
+            },
+            SasState::Done { .. } => {
+                log::info!("Verified device {} of {}", sas.other_device().device_id(), sas.other_user_id());
+                break
+            },
+            SasState::Cancelled(cancel_info) => {
+                log::warn!("Emoji verification with {} was cancelled ({})", sas.other_user_id(), cancel_info.reason());
+                break
+            },
+            _ => { }
+        }
+    }
+
+    pending_verifications.lock().unwrap().remove(sas.other_user_id());
+}
+// A bot can't scan, so the admin scans the code we post into the room. Once scanned the request
+// transitions into the QR verification.
+async fn show_qr_code(request: &VerificationRequest, room: &Room) {
+    let qr = match request.generate_qr_code().await {
+        Ok(Some(qr)) => qr,
+        Ok(None) => return,
+        Err(error) => {
+            log::warn!("Could not generate verification QR code due to {:?}..", error);
+            return
+        }
+    };
+
+    let image = qr.to_bytes()
+                  .map_err(|error| format!("{:?}", error))
+                  .and_then(|bytes| qrcode_generator::to_png_to_vec(bytes, QrCodeEcc::Low, 512)
+                                                     .map_err(|error| format!("{:?}", error)));
+    let image = match image {
+        Ok(image) => image,
+        Err(error) => {
+            log::warn!("Could not render verification QR code due to {}..", error);
+            return
+        }
+    };
+
+    if let Err(error) = room.send_attachment("verification",
+                                             &mime::IMAGE_PNG,
+                                             image,
+                                             AttachmentConfig::new()).await {
+        log::warn!("Could not send verification QR code due to {:?}..", error);
+    }
+}
+
+// Scanning our code only proves the admin's device saw it, the admin still confirms that their side is happy.
+async fn handle_qr_verification(qr: QrVerification, room: Option<Room>, pending_verifications: PendingVerifications) {
+    let mut changes = qr.changes();
+
+    if qr.has_been_scanned() {
+        wait_for_scan_confirmation(&qr, room.as_ref(), &pending_verifications).await;
+    }
+
+    while let Some(state) = changes.next().await {
+        match state {
+            QrVerificationState::Scanned => {
+                wait_for_scan_confirmation(&qr, room.as_ref(), &pending_verifications).await;
+            },
+            QrVerificationState::Done { .. } => {
+                log::info!("Verified device {} of {}", qr.other_device().device_id(), qr.other_user_id());
+                break
+            },
+            QrVerificationState::Cancelled(cancel_info) => {
+                log::warn!("QR verification with {} was cancelled ({})", qr.other_user_id(), cancel_info.reason());
+                break
+            },
+            _ => { }
+        }
+    }
+
+    pending_verifications.lock().unwrap().remove(qr.other_user_id());
+}
+
+async fn wait_for_scan_confirmation(qr: &QrVerification, room: Option<&Room>, pending_verifications: &PendingVerifications) {
+    log::info!("{} scanned the verification QR code, waiting for !verify confirm ..", qr.other_user_id());
+
+    pending_verifications.lock().unwrap().insert(qr.other_user_id().to_owned(), Verification::QrV1(qr.clone()));
+
+    if let Some(room) = room {
+        send_text(room, "The QR code was scanned. If your device confirms the scan reply !verify confirm, otherwise !verify cancel").await;
+    }
+}